@@ -1,8 +1,15 @@
+use crate::conditional::{compute, Defines};
+use crate::object::DEFAULT_SECTION;
 use crate::parser::*;
+use crate::source_map::*;
 use common::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use thiserror::Error;
 
+/// The most ROM the program counter can address
+pub const ADDRESS_SPACE: usize = 0x10000;
+
 pub trait IntoInstruction {
     fn instruction_bits(&self) -> u8;
 }
@@ -34,6 +41,7 @@ impl IntoInstruction for Destination {
             Destination::CarrySet => 0b00_1010_00,
             Destination::CarryReset => 0b00_1011_00,
             Destination::ExpansionSelect => 0b00_1100_00,
+            Destination::Spare(spare) => spare.code() << 2,
         }
     }
 }
@@ -56,55 +64,230 @@ pub enum AssemblerError {
     UnrecognizedLabel { label: String, line: usize },
     #[error("Forbidden instruction, line: {line}")]
     ForbiddenInstruction { line: usize },
+    #[error("Program doesn't fit in {size} bytes of ROM, line: {line}")]
+    TooLarge { size: usize, line: usize },
+    #[error("Code before \".org {address:04X}\" runs past it, line: {line}")]
+    OriginOverlap { address: u16, line: usize },
+}
+
+impl AssemblerError {
+    /// The line the error was found on
+    pub fn line(&self) -> usize {
+        match self {
+            AssemblerError::RepeatLabel { line, .. }
+            | AssemblerError::UnrecognizedLabel { line, .. }
+            | AssemblerError::ForbiddenInstruction { line }
+            | AssemblerError::TooLarge { line, .. }
+            | AssemblerError::OriginOverlap { line, .. } => *line,
+        }
+    }
 }
 
-pub fn assemble(lines: &[(Statement, usize)]) -> Result<Vec<u8>, AssemblerError> {
+/// Pass one: find the address of every label, checking that everything fits in `rom_size`
+/// bytes and that no `.org` goes back over code already placed
+pub fn symbols(
+    lines: &[(Statement, usize)],
+    rom_size: usize,
+) -> Result<HashMap<Label, u16>, AssemblerError> {
     let mut labels = HashMap::new();
-    let mut pc: u16 = 0;
+    let mut pc: usize = 0;
     for (statement, line) in lines {
+        let too_large = AssemblerError::TooLarge {
+            size: rom_size,
+            line: *line,
+        };
         match statement {
             Statement::Label(label) => {
-                if labels.insert(label.clone(), pc).is_some() {
+                if pc >= ADDRESS_SPACE {
+                    Err(too_large)?;
+                }
+                if labels.insert(label.clone(), pc as u16).is_some() {
                     Err(AssemblerError::RepeatLabel {
                         line: *line,
                         label: label.clone(),
                     })?;
                 }
             }
-            Statement::Operation(op) => match op.src {
-                Source::Operand(_) | Source::LabelLo(_) | Source::LabelHi(_) => pc += 2,
-                _ => pc += 1,
-            },
+            Statement::Operation(op) | Statement::Computed(op, _) => {
+                pc += match op.src {
+                    Source::Operand(_) | Source::LabelLo(_) | Source::LabelHi(_) => 2,
+                    _ => 1,
+                };
+                if pc > rom_size {
+                    Err(too_large)?;
+                }
+            }
+            Statement::Origin(address) => {
+                if (*address as usize) < pc {
+                    Err(AssemblerError::OriginOverlap {
+                        address: *address,
+                        line: *line,
+                    })?;
+                }
+                pc = *address as usize;
+                if pc > rom_size {
+                    Err(too_large)?;
+                }
+            }
+            Statement::Text(text) => {
+                pc += 2 * text.len();
+                if pc > rom_size {
+                    Err(too_large)?;
+                }
+            }
+            Statement::Section(_) | Statement::Global(_) | Statement::Extern(_) => (),
         }
     }
+    Ok(labels)
+}
 
+/// Something suspicious that doesn't stop the program from assembling
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    UnusedLabel { label: Label, line: usize },
+}
+
+impl Warning {
+    /// The line the warning is about
+    pub fn line(&self) -> usize {
+        match self {
+            Warning::UnusedLabel { line, .. } => *line,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::UnusedLabel { label, line } => {
+                write!(f, "Label {} is never used, line: {}", label, line)
+            }
+        }
+    }
+}
+
+/// Everything produced by assembling a program
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Assembly {
+    pub bytes: Vec<u8>,
+    pub symbols: HashMap<Label, u16>,
+    /// Statements on line 0 were generated by the assembler rather than written in the source,
+    /// so they are left out
+    pub source_map: SourceMap,
+    pub warnings: Vec<Warning>,
+    /// Bytes of code in each section, in the order the sections first appear. `.org` padding
+    /// isn't counted.
+    pub usage: Vec<(String, usize)>,
+}
+
+/// Pass two: emit the bytecode in source order. `.section` only decides which section's usage
+/// the code counts towards, and `.extern` labels still have to be defined, see
+/// `object::assemble` to assemble for the linker instead.
+/// Expressions left in `Statement::Computed` are evaluated with no symbols defined.
+pub fn assemble(lines: &[(Statement, usize)], rom_size: usize) -> Result<Assembly, AssemblerError> {
+    let labels = symbols(lines, rom_size)?;
     let mut bytecode = Vec::new();
+    let mut source_map = SourceMap::default();
+    let mut used = HashSet::new();
+    let mut usage = vec![(DEFAULT_SECTION.to_string(), 0)];
+    let mut section = 0;
     for (statement, line) in lines {
-        let op = match statement {
-            Statement::Operation(op) => op,
-            Statement::Label(_) => continue,
-        };
-        bytecode.push(op.instruction_bits());
-        let get_label_pc = |label: &String| match labels.get(label) {
-            Some(pc) => Ok(*pc),
-            None => Err(AssemblerError::UnrecognizedLabel {
-                label: label.clone(),
-                line: *line,
-            }),
+        let generated;
+        let ops = match statement {
+            Statement::Operation(op) => std::slice::from_ref(op),
+            Statement::Computed(op, expression) => {
+                generated = vec![compute(op, expression, &Defines::new())];
+                &generated[..]
+            }
+            Statement::Text(text) => {
+                generated = text_operations(text);
+                &generated[..]
+            }
+            Statement::Global(label) => {
+                if !labels.contains_key(label) {
+                    Err(AssemblerError::UnrecognizedLabel {
+                        label: label.clone(),
+                        line: *line,
+                    })?;
+                }
+                used.insert(label.clone());
+                continue;
+            }
+            Statement::Section(name) => {
+                section = match usage.iter().position(|(section, _)| section == name) {
+                    Some(index) => index,
+                    None => {
+                        usage.push((name.clone(), 0));
+                        usage.len() - 1
+                    }
+                };
+                continue;
+            }
+            Statement::Label(_) | Statement::Extern(_) => continue,
+            Statement::Origin(address) => {
+                bytecode.resize(*address as usize, NOP_BYTE);
+                source_map.bytes.resize(*address as usize, None);
+                continue;
+            }
         };
-        match op.src {
-            Source::Operand(op) => bytecode.push(op),
-            Source::LabelHi(ref label) => bytecode.push((get_label_pc(label)? >> 8) as u8),
-            Source::LabelLo(ref label) => bytecode.push((get_label_pc(label)? & 0x00FF) as u8),
-            _ => (),
-        }
-        if let Source::Operand(_) | Source::LabelLo(_) | Source::LabelHi(_) = op.src {
-            if op.dest == Destination::Memory {
-                Err(AssemblerError::ForbiddenInstruction { line: *line })?;
+        for op in ops {
+            let mapped = |kind| match *line {
+                0 => None,
+                line => Some(MappedByte {
+                    location: Location { file: None, line },
+                    kind,
+                }),
+            };
+            bytecode.push(op.instruction_bits());
+            source_map.bytes.push(mapped(ByteKind::Opcode));
+            let mut get_label_pc = |label: &String| match labels.get(label) {
+                Some(pc) => {
+                    used.insert(label.clone());
+                    Ok(*pc)
+                }
+                None => Err(AssemblerError::UnrecognizedLabel {
+                    label: label.clone(),
+                    line: *line,
+                }),
+            };
+            match op.src {
+                Source::Operand(op) => bytecode.push(op),
+                Source::LabelHi(ref label) => bytecode.push((get_label_pc(label)? >> 8) as u8),
+                Source::LabelLo(ref label) => bytecode.push((get_label_pc(label)? & 0x00FF) as u8),
+                _ => (),
+            }
+            if let Source::Operand(_) | Source::LabelLo(_) | Source::LabelHi(_) = op.src {
+                source_map.bytes.push(mapped(ByteKind::Operand));
+                if op.dest == Destination::Memory {
+                    Err(AssemblerError::ForbiddenInstruction { line: *line })?;
+                }
             }
+            usage[section].1 += match op.src {
+                Source::Operand(_) | Source::LabelLo(_) | Source::LabelHi(_) => 2,
+                _ => 1,
+            };
         }
     }
-    Ok(bytecode)
+    usage.retain(|(_, bytes)| *bytes > 0);
+
+    let warnings = lines
+        .iter()
+        .filter_map(|(statement, line)| match statement {
+            Statement::Label(label) if !used.contains(label) => Some(Warning::UnusedLabel {
+                label: label.clone(),
+                line: *line,
+            }),
+            _ => None,
+        })
+        .collect();
+
+    Ok(Assembly {
+        bytes: bytecode,
+        symbols: labels,
+        source_map,
+        warnings,
+        usage,
+    })
 }
 
 #[cfg(test)]
@@ -132,6 +315,16 @@ mod tests {
             .instruction_bits(),
             0b11_1010_01
         );
+        assert_eq!(
+            Operation {
+                src: Source::Accumulator,
+                dest: Destination::Spare(SpareDestination::E),
+                cond_1: true,
+                cond_carry: false
+            }
+            .instruction_bits(),
+            0b01_1110_10
+        );
     }
 
     #[test]
@@ -173,6 +366,67 @@ mod tests {
             0b11_0101_00,
             0b0000000000,
         ];
-        assert_eq!(assemble(&instructions), Ok(expected_bytecode));
+        let assembly = assemble(&instructions, ADDRESS_SPACE).unwrap();
+        assert_eq!(assembly.bytes, expected_bytecode);
+        let source_map = assembly
+            .source_map
+            .bytes
+            .iter()
+            .map(|byte| byte.as_ref().map(|byte| (byte.location.line, byte.kind)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            source_map,
+            vec![
+                Some((1, ByteKind::Opcode)),
+                Some((2, ByteKind::Opcode)),
+                Some((2, ByteKind::Operand)),
+                Some((3, ByteKind::Opcode)),
+                Some((3, ByteKind::Operand)),
+            ]
+        );
+        assert!(assembly.warnings.is_empty());
+        assert_eq!(assembly.usage, vec![(DEFAULT_SECTION.to_string(), 5)]);
+    }
+
+    #[test]
+    fn test_rom_size() {
+        let led = Operation {
+            dest: Destination::Led,
+            ..NOP
+        };
+        let op = |line| (Statement::Operation(led.clone()), line);
+        let lines = [op(1), (Statement::Origin(0x0003), 2), op(3)];
+        // The gap is filled with NOPs
+        assert_eq!(
+            assemble(&lines, 4).unwrap().bytes,
+            vec![0x64, NOP_BYTE, NOP_BYTE, 0x64]
+        );
+        assert_eq!(
+            assemble(&lines, 3),
+            Err(AssemblerError::TooLarge { size: 3, line: 3 })
+        );
+        assert_eq!(
+            symbols(&[(Statement::Origin(0x0010), 1)], 8),
+            Err(AssemblerError::TooLarge { size: 8, line: 1 })
+        );
+
+        let lines = [op(1), op(2), (Statement::Origin(0x0001), 3), op(4)];
+        assert_eq!(
+            assemble(&lines, ADDRESS_SPACE),
+            Err(AssemblerError::OriginOverlap {
+                address: 0x0001,
+                line: 3
+            })
+        );
+
+        // The whole address space is always the limit, rather than wrapping around
+        let lines = [(Statement::Origin(0xFFFF), 1), op(2), op(3)];
+        assert_eq!(
+            symbols(&lines, ADDRESS_SPACE),
+            Err(AssemblerError::TooLarge {
+                size: ADDRESS_SPACE,
+                line: 3
+            })
+        );
     }
 }
@@ -0,0 +1,196 @@
+//! Conditional assembly with `.if`, `.ifdef`, `.ifndef`, `.else` and `.endif`, and the
+//! expressions they share with `.rept` and computed operands
+
+use crate::parser::{Conditional, Expression, Operator};
+use common::*;
+use std::collections::HashMap;
+
+/// Symbols that `.if` and `.ifdef` can test, usually from `-D NAME=VALUE`
+pub type Defines = HashMap<String, i64>;
+
+/// Parse `NAME=VALUE` or just `NAME`, which defines it as 1. Values are decimal, or hex with
+/// `0x`.
+pub fn parse_define(s: &str) -> Result<(String, i64), String> {
+    let (name, value) = match s.find('=') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, "1"),
+    };
+    if !is_label(name) {
+        return Err(format!("Invalid symbol name \"{}\"", name));
+    }
+    let value = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    match value {
+        Ok(value) => Ok((name.to_string(), value)),
+        Err(_) => Err(format!("Invalid value for {}", name)),
+    }
+}
+
+/// Evaluate an expression the way C does: comparisons and `!` give 1 or 0, and symbols that
+/// aren't defined are 0. Arithmetic wraps around rather than overflowing.
+pub fn evaluate(expression: &Expression, defines: &Defines) -> i64 {
+    match expression {
+        Expression::Number(n) => *n,
+        Expression::Symbol(name) => defines.get(name).copied().unwrap_or(0),
+        Expression::Not(e) => (evaluate(e, defines) == 0) as i64,
+        Expression::Binary(left, operator, right) => {
+            let (left, right) = (evaluate(left, defines), evaluate(right, defines));
+            let result = match operator {
+                Operator::Add => return left.wrapping_add(right),
+                Operator::Subtract => return left.wrapping_sub(right),
+                Operator::Multiply => return left.wrapping_mul(right),
+                Operator::Or => left != 0 || right != 0,
+                Operator::And => left != 0 && right != 0,
+                Operator::Equal => left == right,
+                Operator::NotEqual => left != right,
+                Operator::Less => left < right,
+                Operator::LessEqual => left <= right,
+                Operator::Greater => left > right,
+                Operator::GreaterEqual => left >= right,
+            };
+            result as i64
+        }
+    }
+}
+
+/// `op` with its operand set to the low byte of `expression`
+pub fn compute(op: &Operation, expression: &Expression, defines: &Defines) -> Operation {
+    Operation {
+        src: Source::Operand(evaluate(expression, defines) as u8),
+        ..op.clone()
+    }
+}
+
+#[derive(Debug)]
+struct Branch {
+    condition: bool,
+    in_else: bool,
+    line: usize,
+}
+
+/// Keeps track of nested conditionals while going through a file line by line
+#[derive(Debug, Default)]
+pub struct Conditions {
+    branches: Vec<Branch>,
+}
+
+impl Conditions {
+    /// Whether lines here should be assembled
+    pub fn active(&self) -> bool {
+        self.branches.iter().all(|b| b.condition != b.in_else)
+    }
+
+    pub fn update(
+        &mut self,
+        conditional: &Conditional,
+        defines: &Defines,
+        line: usize,
+    ) -> Result<(), String> {
+        let condition = match conditional {
+            Conditional::If(expression) => evaluate(expression, defines) != 0,
+            Conditional::IfDef(name) => defines.contains_key(name),
+            Conditional::IfNDef(name) => !defines.contains_key(name),
+            Conditional::Else => {
+                return match self.branches.last_mut() {
+                    Some(branch) if !branch.in_else => {
+                        branch.in_else = true;
+                        Ok(())
+                    }
+                    Some(branch) => Err(format!(
+                        "Second \".else\" for the \".if\" on line {}",
+                        branch.line
+                    )),
+                    None => Err("\".else\" without \".if\"".to_string()),
+                };
+            }
+            Conditional::EndIf => {
+                return match self.branches.pop() {
+                    Some(_) => Ok(()),
+                    None => Err("\".endif\" without \".if\"".to_string()),
+                };
+            }
+        };
+        self.branches.push(Branch {
+            condition,
+            in_else: false,
+            line,
+        });
+        Ok(())
+    }
+
+    /// The line of the innermost `.if` that hasn't been closed yet
+    pub fn unclosed(&self) -> Option<usize> {
+        self.branches.last().map(|branch| branch.line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_conditional, Syntax};
+
+    #[test]
+    fn test_evaluate() {
+        let defines = vec![("REV".to_string(), 2), ("SERIAL".to_string(), 1)]
+            .into_iter()
+            .collect();
+        let evaluate = |text| {
+            let (_, conditional) = parse_conditional(text, Syntax::default()).unwrap();
+            match conditional {
+                Conditional::If(expression) => evaluate(&expression, &defines),
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(evaluate(".if REV"), 2);
+        assert_eq!(evaluate(".if REV == 2 && SERIAL"), 1);
+        assert_eq!(evaluate(".if REV < 2 || !SERIAL"), 0);
+        assert_eq!(evaluate(".if MISSING"), 0);
+        assert_eq!(evaluate(".if !(REV >= 3)"), 1);
+        assert_eq!(evaluate(".if REV * 3 - 1 == 5"), 1);
+        assert_eq!(evaluate(".if 1 + REV * 0x10"), 0x21);
+
+        assert_eq!(parse_define("REV=2"), Ok(("REV".into(), 2)));
+        assert_eq!(parse_define("MASK=0xF0"), Ok(("MASK".into(), 0xF0)));
+        assert_eq!(parse_define("SERIAL"), Ok(("SERIAL".into(), 1)));
+        assert!(parse_define("REV=two").is_err());
+        assert!(parse_define("=2").is_err());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let defines = Defines::new();
+        let mut conditions = Conditions::default();
+        let mut active = Vec::new();
+        let lines = [
+            Conditional::IfNDef("SERIAL".into()),
+            Conditional::IfDef("SERIAL".into()),
+            Conditional::Else,
+            Conditional::EndIf,
+            Conditional::Else,
+            Conditional::EndIf,
+        ];
+        for (line, conditional) in lines.iter().enumerate() {
+            conditions.update(conditional, &defines, line + 1).unwrap();
+            active.push(conditions.active());
+        }
+        assert_eq!(active, vec![true, false, true, true, false, true]);
+        assert_eq!(conditions.unclosed(), None);
+
+        assert!(conditions.update(&Conditional::Else, &defines, 7).is_err());
+        assert!(conditions.update(&Conditional::EndIf, &defines, 7).is_err());
+        conditions
+            .update(&Conditional::IfDef("X".into()), &defines, 8)
+            .unwrap();
+        conditions.update(&Conditional::Else, &defines, 9).unwrap();
+        assert_eq!(
+            conditions.update(&Conditional::Else, &defines, 10),
+            Err("Second \".else\" for the \".if\" on line 8".into())
+        );
+        assert_eq!(conditions.unclosed(), Some(8));
+    }
+}
@@ -0,0 +1,274 @@
+//! Laying a ROM image out across EEPROM chips for programming
+
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ImageError {
+    #[error("The image is {size} bytes, but the chips only hold {capacity}")]
+    TooLarge { size: usize, capacity: usize },
+    #[error("Splitting an image needs a chip size other than 0")]
+    NoChipSize,
+    #[error("{count} chips of {size} bytes hold more than can be addressed")]
+    Overflow { size: usize, count: usize },
+}
+
+/// How the image is divided between chips
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// One chip holding the whole image
+    Single,
+    /// Consecutive address ranges of one chip size each, on as many chips as it takes
+    Split,
+    /// Even addresses on one chip and odd addresses on the other
+    Interleave,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageOptions {
+    /// Pad each chip to this many bytes. Without it chips are only as large as their contents.
+    pub chip_size: Option<usize>,
+    /// The byte to pad the end of each chip with. Gaps that `.org` leaves inside the image are
+    /// already filled with NOPs, so that running into one is harmless.
+    pub fill: u8,
+    pub layout: Layout,
+    /// How many chips `Layout::Split` may use, or as many as it takes if `None`
+    pub chips: Option<usize>,
+}
+
+impl Default for ImageOptions {
+    /// One chip, no padding, and 0xFF like an erased EEPROM if a size is given
+    fn default() -> Self {
+        Self {
+            chip_size: None,
+            fill: 0xFF,
+            layout: Layout::Single,
+            chips: None,
+        }
+    }
+}
+
+/// The contents of one chip
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chip {
+    /// Added to the output file name to tell the chips apart, `None` when there's only one
+    pub suffix: Option<String>,
+    /// The first and last address of the image on this chip
+    pub start: usize,
+    pub end: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl Chip {
+    /// The 16 bit sum of every byte, as EEPROM programmers show it
+    pub fn checksum(&self) -> u16 {
+        self.bytes
+            .iter()
+            .fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16))
+    }
+}
+
+/// Divide `image` between chips as `options` asks, padding each one with the fill byte
+pub fn chips(image: &[u8], options: &ImageOptions) -> Result<Vec<Chip>, ImageError> {
+    let pad = |mut bytes: Vec<u8>| {
+        if let Some(size) = options.chip_size {
+            bytes.resize(size, options.fill);
+        }
+        bytes
+    };
+    let too_large = |capacity| ImageError::TooLarge {
+        size: image.len(),
+        capacity,
+    };
+    let capacity = |size: usize, count: usize| {
+        size.checked_mul(count)
+            .ok_or(ImageError::Overflow { size, count })
+    };
+    match options.layout {
+        Layout::Single => {
+            if let Some(size) = options.chip_size.filter(|size| image.len() > *size) {
+                return Err(too_large(size));
+            }
+            let bytes = pad(image.to_vec());
+            Ok(vec![Chip {
+                suffix: None,
+                start: 0,
+                end: bytes.len().saturating_sub(1),
+                bytes,
+            }])
+        }
+        Layout::Split => {
+            let size = options
+                .chip_size
+                .filter(|size| *size > 0)
+                .ok_or(ImageError::NoChipSize)?;
+            if let Some(count) = options.chips {
+                let capacity = capacity(size, count)?;
+                if image.len() > capacity {
+                    return Err(too_large(capacity));
+                }
+            }
+            // An empty image still gets a chip, filled with padding
+            let count = image.len().div_ceil(size).max(1);
+            Ok((0..count)
+                .map(|index| {
+                    let start = index * size;
+                    let end = image.len().min(start.saturating_add(size));
+                    Chip {
+                        suffix: Some(index.to_string()),
+                        start,
+                        end: start + (size - 1),
+                        bytes: pad(image[start.min(end)..end].to_vec()),
+                    }
+                })
+                .collect())
+        }
+        Layout::Interleave => {
+            if let Some(size) = options.chip_size {
+                let capacity = capacity(size, 2)?;
+                if image.len() > capacity {
+                    return Err(too_large(capacity));
+                }
+            }
+            let half = |parity: usize| {
+                let bytes = pad(image.iter().skip(parity).step_by(2).copied().collect());
+                Chip {
+                    suffix: Some(["even", "odd"][parity].to_string()),
+                    start: parity,
+                    end: (parity + bytes.len() * 2).saturating_sub(2),
+                    bytes,
+                }
+            };
+            Ok(vec![half(0), half(1)])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chips() {
+        let image = [1, 2, 3, 4, 5];
+        let single = chips(
+            &image,
+            &ImageOptions {
+                chip_size: Some(8),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(single[0].bytes, vec![1, 2, 3, 4, 5, 0xFF, 0xFF, 0xFF]);
+        assert_eq!((single[0].start, single[0].end), (0, 7));
+        assert_eq!(single[0].checksum(), 15 + 3 * 0xFF);
+
+        let split = chips(
+            &image,
+            &ImageOptions {
+                chip_size: Some(2),
+                fill: 0x00,
+                layout: Layout::Split,
+                chips: Some(3),
+            },
+        )
+        .unwrap();
+        let contents = split
+            .iter()
+            .map(|chip| (chip.suffix.as_deref().unwrap(), chip.start, &chip.bytes[..]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contents,
+            vec![("0", 0, &[1, 2][..]), ("1", 2, &[3, 4]), ("2", 4, &[5, 0])]
+        );
+
+        let interleaved = chips(
+            &image,
+            &ImageOptions {
+                chip_size: Some(4),
+                layout: Layout::Interleave,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(interleaved[0].bytes, vec![1, 3, 5, 0xFF]);
+        assert_eq!(interleaved[1].bytes, vec![2, 4, 0xFF, 0xFF]);
+        assert_eq!((interleaved[0].start, interleaved[0].end), (0, 6));
+        assert_eq!((interleaved[1].start, interleaved[1].end), (1, 7));
+
+        // Without a chip size nothing is padded
+        assert_eq!(chips(&image, &Default::default()).unwrap()[0].bytes, image);
+    }
+
+    #[test]
+    fn test_chip_errors() {
+        let options = |chip_size, layout| ImageOptions {
+            chip_size,
+            layout,
+            ..Default::default()
+        };
+        assert_eq!(
+            chips(&[0; 5], &options(Some(4), Layout::Single)),
+            Err(ImageError::TooLarge {
+                size: 5,
+                capacity: 4
+            })
+        );
+        assert_eq!(
+            chips(&[0; 5], &options(Some(2), Layout::Interleave)),
+            Err(ImageError::TooLarge {
+                size: 5,
+                capacity: 4
+            })
+        );
+        assert_eq!(
+            chips(&[0; 5], &options(None, Layout::Split)),
+            Err(ImageError::NoChipSize)
+        );
+        assert_eq!(
+            chips(&[0; 5], &options(Some(0), Layout::Split)),
+            Err(ImageError::NoChipSize)
+        );
+        assert_eq!(
+            chips(
+                &[0; 5],
+                &ImageOptions {
+                    chips: Some(2),
+                    ..options(Some(2), Layout::Split)
+                }
+            ),
+            Err(ImageError::TooLarge {
+                size: 5,
+                capacity: 4
+            })
+        );
+        assert_eq!(
+            chips(
+                &[0; 5],
+                &ImageOptions {
+                    chips: Some(usize::MAX),
+                    ..options(Some(2), Layout::Split)
+                }
+            ),
+            Err(ImageError::Overflow {
+                size: 2,
+                count: usize::MAX
+            })
+        );
+    }
+
+    #[test]
+    fn test_empty_split() {
+        let split = chips(
+            &[],
+            &ImageOptions {
+                chip_size: Some(4),
+                layout: Layout::Split,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(split.len(), 1);
+        assert_eq!(split[0].bytes, vec![0xFF; 4]);
+        assert_eq!((split[0].start, split[0].end), (0, 3));
+    }
+}
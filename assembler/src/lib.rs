@@ -1,33 +1,276 @@
-use crate::parser::*;
-use anyhow::{bail, Result};
+use crate::conditional::{compute, evaluate, Conditions, Defines};
+use crate::object::Object;
+use crate::source_map::*;
 use common::*;
-mod assembler;
-mod parser;
+use std::collections::HashMap;
+use thiserror::Error;
+pub(crate) mod assembler;
+pub mod conditional;
+pub mod image;
+pub mod object;
+pub(crate) mod parser;
+pub mod repeat;
+pub mod source_map;
 
-pub fn assemble(text: &str) -> Result<Vec<u8>> {
-    let lines = text.lines().map(|s| s.to_string()).collect::<Vec<_>>();
+pub use crate::assembler::{AssemblerError, Assembly, IntoInstruction, Warning, ADDRESS_SPACE};
+pub use crate::parser::{
+    explain_error, Conditional, Expression, Operator, Repeat, Statement, Syntax,
+};
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("Parser error on line {line}; {message}")]
+    Parse { line: usize, message: String },
+    #[error(transparent)]
+    Assembler(#[from] AssemblerError),
+}
+
+/// How to assemble a program
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// How the board comes out of reset
+    pub reset: Reset,
+    /// Which variations on the syntax to accept
+    pub syntax: Syntax,
+    /// Symbols for `.if` and `.ifdef`
+    pub defines: Defines,
+    /// Bytes of ROM on the board, or the whole address space if `None`
+    pub rom_size: Option<usize>,
+}
+
+impl Options {
+    pub fn rom_size(&self) -> usize {
+        self.rom_size.unwrap_or(ADDRESS_SPACE)
+    }
+}
+
+pub fn assemble(text: &str) -> Result<Vec<u8>, Error> {
+    Ok(assemble_for(text, &Options::default())?.bytes)
+}
+
+/// Assemble `text`, placing the program at the reset vector and starting it with a dummy NOP if
+/// the board needs one. The source map follows any `%line` directives left by the preprocessor.
+pub fn assemble_for(text: &str, options: &Options) -> Result<Assembly, Error> {
+    let mut statements = prologue(&options.reset);
+    statements.extend(parse(text, options)?);
+    let mut assembly = assemble_statements(&statements, options.rom_size())?;
+    assembly.source_map.remap(&Lines::new(text));
+    Ok(assembly)
+}
+
+/// Assemble `text` into a relocatable object for the linker, which takes care of placing it and
+/// of the reset behavior
+pub fn assemble_object(text: &str, options: &Options) -> Result<Object, Error> {
+    Ok(object::assemble(&parse(text, options)?)?)
+}
+
+/// Assemble statements that have already been parsed or generated, each paired with its line
+/// number. Nothing is added in front of them, see `prologue`.
+pub fn assemble_statements(
+    statements: &[(Statement, usize)],
+    rom_size: usize,
+) -> Result<Assembly, Error> {
+    Ok(assembler::assemble(statements, rom_size)?)
+}
+
+/// Parse `text` into statements, each paired with its line number. Lines in the inactive
+/// branches of `.if`s are left out, `.rept` blocks are expanded and computed operands are
+/// evaluated.
+pub fn parse(text: &str, options: &Options) -> Result<Vec<(Statement, usize)>, Error> {
+    let lines = text
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line))
+        .collect::<Vec<_>>();
     let mut statements = Vec::new();
+    let mut iterations = 0;
+    parse_lines(
+        &lines,
+        options,
+        &options.defines,
+        &mut statements,
+        &mut iterations,
+    )?;
+    Ok(statements)
+}
 
-    statements.push((
-        Statement::Operation(Operation {
-            src: Source::Accumulator,
-            dest: Destination::Accumulator,
-            cond_carry: false,
-            cond_1: false,
+/// Parse numbered lines with the given symbols defined. `iterations` counts the `.rept`
+/// iterations expanded so far, which together with the size of the ROM limits how much
+/// nested blocks can expand to.
+fn parse_lines(
+    lines: &[(usize, &str)],
+    options: &Options,
+    defines: &Defines,
+    statements: &mut Vec<(Statement, usize)>,
+    iterations: &mut usize,
+) -> Result<(), Error> {
+    let mut conditions = Conditions::default();
+    let mut index = 0;
+    while let Some(&(line_number, line)) = lines.get(index) {
+        index += 1;
+        let error = |message| Error::Parse {
+            line: line_number,
+            message,
+        };
+        if let Some(conditional) = parse_conditional(line, options.syntax) {
+            conditions
+                .update(&conditional, defines, line_number)
+                .map_err(error)?;
+            continue;
+        }
+        if !conditions.active() {
+            continue;
+        }
+        match parse_repeat(line, options.syntax) {
+            Some(Repeat::Start { count, counter }) => {
+                let end = repeat::find_end(&lines[index..], options.syntax)
+                    .ok_or_else(|| error("Missing \".endr\"".to_string()))?;
+                let count = evaluate(&count, defines);
+                if !(0..=0x10000).contains(&count) {
+                    return Err(error(format!("Can't repeat {} times", count)));
+                }
+                let body = &lines[index..index + end];
+                for iteration in 0..count {
+                    *iterations += 1;
+                    if *iterations > ADDRESS_SPACE {
+                        return Err(error(format!(
+                            "Repeats more than {} times in total",
+                            ADDRESS_SPACE
+                        )));
+                    }
+                    let mut defines = defines.clone();
+                    if let Some(counter) = &counter {
+                        defines.insert(counter.clone(), iteration);
+                    }
+                    let start = statements.len();
+                    parse_lines(body, options, &defines, statements, iterations)?;
+                    repeat::rename_labels(&mut statements[start..], iteration);
+                    if statements.len() > options.rom_size() {
+                        return Err(error(format!(
+                            "Expands to more statements than fit in {} bytes of ROM",
+                            options.rom_size()
+                        )));
+                    }
+                }
+                index += end + 1;
+                continue;
+            }
+            Some(Repeat::End) => return Err(error("\".endr\" without \".rept\"".to_string())),
+            None => (),
+        }
+        match parse_line(line, options.syntax).map_err(error)? {
+            (Some(Statement::Computed(op, expression)), _) => {
+                let op = compute(&op, &expression, defines);
+                statements.push((Statement::Operation(op), line_number));
+            }
+            (Some(s), _) => statements.push((s, line_number)),
+            (None, _) => (),
+        }
+    }
+    match conditions.unclosed() {
+        Some(line) => Err(Error::Parse {
+            line,
+            message: "Missing \".endif\"".to_string(),
         }),
-        0,
+        None => Ok(()),
+    }
+}
+
+/// Parse one line of source into the statement on it, if any, and the rest of the line, which is
+/// either empty or a comment. If the line doesn't parse, the error explains why.
+pub fn parse_line(line: &str, syntax: Syntax) -> Result<(Option<Statement>, &str), String> {
+    parser::parse_line(line, syntax)
+        .map(|(rest, statement)| (statement, rest))
+        .map_err(|_| explain_error(line, syntax))
+}
+
+/// Parse a line holding a conditional directive such as `.ifdef` or `.endif`
+pub fn parse_conditional(line: &str, syntax: Syntax) -> Option<Conditional> {
+    parser::parse_conditional(line, syntax)
+        .ok()
+        .map(|(_, conditional)| conditional)
+}
+
+/// Parse a line holding `.rept` or `.endr`
+pub fn parse_repeat(line: &str, syntax: Syntax) -> Option<Repeat> {
+    parser::parse_repeat(line, syntax)
+        .ok()
+        .map(|(_, repeat)| repeat)
+}
+
+/// Parse an expression in brackets, such as a computed operand, from the start of `text`,
+/// returning it and the rest of `text`
+pub fn parse_bracketed(text: &str) -> Option<(Expression, &str)> {
+    parser::parse_bracketed(text)
+        .ok()
+        .map(|(rest, expression)| (expression, rest))
+}
+
+/// Statements that start every program on a board with the given reset behavior, on line 0
+pub fn prologue(reset: &Reset) -> Vec<(Statement, usize)> {
+    let mut statements = Vec::new();
+    if reset.vector != 0 {
+        statements.push((Statement::Origin(reset.vector), 0));
+    }
+    if reset.skip_first_fetch {
+        statements.push((Statement::Operation(NOP), 0));
+    }
+    statements
+}
+
+/// Write a symbol map as one `ADDR label` line per label, sorted by address
+pub fn format_symbols(symbols: &HashMap<Label, u16>) -> String {
+    let mut symbols = symbols.iter().collect::<Vec<_>>();
+    symbols.sort_by_key(|(label, addr)| (**addr, label.as_str()));
+    symbols
+        .into_iter()
+        .map(|(label, addr)| format!("{:04X} {}\n", addr, label))
+        .collect()
+}
+
+/// Summarize how much of a `rom_size` byte ROM each section uses and how much is left
+pub fn format_usage(assembly: &Assembly, rom_size: usize) -> String {
+    let width = assembly
+        .usage
+        .iter()
+        .map(|(section, _)| section.len())
+        .chain(Some("total".len()))
+        .max()
+        .unwrap_or(0);
+    let mut out = String::new();
+    for (section, bytes) in &assembly.usage {
+        out.push_str(&format!("{:<w$} {:>5} bytes\n", section, bytes, w = width));
+    }
+    let used = assembly.usage.iter().map(|(_, bytes)| bytes).sum::<usize>();
+    out.push_str(&format!(
+        "{:<w$} {:>5} bytes, {} of {} free\n",
+        "total",
+        used,
+        rom_size.saturating_sub(used),
+        rom_size,
+        w = width
     ));
+    out
+}
 
-    for (line_number, line) in lines.iter().enumerate() {
-        let line_number = line_number + 1;
-        match parse_line(&line) {
-            Err(e) => bail!("Parser error on line {}; {:?}", line_number, e),
-            Ok((_, Some(s))) => statements.push((s, line_number)),
-            _ => (),
+/// Write a source map as one `ADDR opcode|operand file:line` line per mapped byte. Lines of the
+/// input itself are attributed to `input`.
+pub fn format_source_map(source_map: &SourceMap, input: &str) -> String {
+    let mut out = String::new();
+    for (addr, byte) in source_map.bytes.iter().enumerate() {
+        if let Some(byte) = byte {
+            let kind = match byte.kind {
+                ByteKind::Opcode => "opcode",
+                ByteKind::Operand => "operand",
+            };
+            let file = byte.location.file.as_deref().unwrap_or(input);
+            out.push_str(&format!(
+                "{:04X} {} {}:{}\n",
+                addr, kind, file, byte.location.line
+            ));
         }
     }
-
-    Ok(assembler::assemble(statements.as_slice())?)
+    out
 }
 
 #[cfg(test)]
@@ -108,6 +351,329 @@ lo@main_loop -> PC";
         assert_eq!(assemble(&text).unwrap(), binary);
     }
 
+    #[test]
+    fn test_symbols() {
+        let text = "
+start:
+5F -> LED
+end:
+lo@start -> PC";
+        let symbols = assemble_for(text, &Options::default()).unwrap().symbols;
+        assert_eq!(symbols["start"], 0x0001);
+        assert_eq!(symbols["end"], 0x0003);
+        assert_eq!(format_symbols(&symbols), "0001 start\n0003 end\n");
+    }
+
+    #[test]
+    fn test_rom_size() {
+        let text = "
+5F -> LED
+.section data
+.org 0010
+table:
+'a' -> Serial.out";
+        let options = Options {
+            rom_size: Some(0x20),
+            ..Default::default()
+        };
+        let assembly = assemble_for(text, &options).unwrap();
+        assert_eq!(assembly.symbols["table"], 0x0010);
+        assert_eq!(assembly.bytes.len(), 0x12);
+        assert_eq!(
+            format_usage(&assembly, 0x20),
+            "text      3 bytes\ndata      2 bytes\ntotal     5 bytes, 27 of 32 free\n"
+        );
+
+        let options = Options {
+            rom_size: Some(0x10),
+            ..Default::default()
+        };
+        assert_eq!(
+            assemble_for(text, &options),
+            Err(Error::Assembler(AssemblerError::TooLarge {
+                size: 0x10,
+                line: 6
+            }))
+        );
+        assert_eq!(
+            assemble(".org 0004\n.org 0002"),
+            Err(Error::Assembler(AssemblerError::OriginOverlap {
+                address: 0x0002,
+                line: 2
+            }))
+        );
+    }
+
+    #[test]
+    fn test_reset() {
+        let text = "
+start:
+lo@start -> PC";
+        let options = Options {
+            reset: Reset {
+                skip_first_fetch: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let assembly = assemble_for(text, &options).unwrap();
+        assert_eq!(assembly.bytes, vec![0b11_0101_00, 0x00]);
+        assert_eq!(assembly.symbols["start"], 0x0000);
+
+        let options = Options {
+            reset: Reset {
+                vector: 0x0004,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let assembly = assemble_for(text, &options).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                NOP_BYTE,
+                NOP_BYTE,
+                NOP_BYTE,
+                NOP_BYTE,
+                NOP_BYTE,
+                0b11_0101_00,
+                0x05
+            ]
+        );
+        assert_eq!(assembly.symbols["start"], 0x0005);
+        assert_eq!(assembly.source_map.get(4), None);
+        assert_eq!(assembly.source_map.get(5).unwrap().location.line, 3);
+    }
+
+    #[test]
+    fn test_source_map() {
+        let text = "ACC -> LED
+%line 1+1 main.s
+ACC -> ACC
+%line 7+0 macros.s
+5F -> LED";
+        let assembly = assemble_for(text, &Options::default()).unwrap();
+        assert_eq!(
+            format_source_map(&assembly.source_map, "out.s"),
+            "0001 opcode out.s:1\n\
+            0002 opcode main.s:1\n\
+            0003 opcode macros.s:7\n\
+            0004 operand macros.s:7\n"
+        );
+    }
+
+    #[test]
+    fn test_statements() {
+        let mut statements = parse("start:\nunused:\n5F -> LED", &Options::default()).unwrap();
+        statements.push((Statement::Operation("lo@start -> PC".parse().unwrap()), 4));
+        let assembly = assemble_statements(&statements, ADDRESS_SPACE).unwrap();
+        assert_eq!(assembly.bytes, vec![0b11_1001_00, 0x5F, 0b11_0101_00, 0x00]);
+        assert_eq!(
+            assembly.warnings,
+            vec![Warning::UnusedLabel {
+                label: "unused".into(),
+                line: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            assemble("ACC -> ACC\nACC.plus -> LED"),
+            Err(Error::Parse { line: 2, .. })
+        ));
+        assert_eq!(
+            assemble("lo@nowhere -> PC"),
+            Err(Error::Assembler(AssemblerError::UnrecognizedLabel {
+                label: "nowhere".into(),
+                line: 1
+            }))
+        );
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let text = "
+.ifdef SERIAL
+print:
+    ACC -> Serial.out
+.else
+print: // Show it on the LEDs instead
+    .if BOARD_REV >= 2
+    ACC -> LED
+    .else
+    ACC -> RAM.low
+    .endif
+.endif
+lo@print -> PC";
+        let assemble = |defines: &[(&str, i64)]| {
+            let options = Options {
+                defines: defines.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+                ..Default::default()
+            };
+            assemble_for(text, &options).map(|a| a.bytes[1])
+        };
+        assert_eq!(assemble(&[("SERIAL", 1)]), Ok(0b01_1000_00));
+        assert_eq!(assemble(&[("BOARD_REV", 2)]), Ok(0b01_1001_00));
+        assert_eq!(assemble(&[]), Ok(0b01_0110_00));
+
+        // Labels only exist in the branch that's assembled
+        let text = ".ifdef SERIAL\nprint:\n.endif\nlo@print -> PC";
+        assert!(matches!(
+            assemble_for(text, &Options::default()),
+            Err(Error::Assembler(AssemblerError::UnrecognizedLabel { .. }))
+        ));
+        assert_eq!(
+            super::assemble(".if 1\n.if 0\n.endif"),
+            Err(Error::Parse {
+                line: 1,
+                message: "Missing \".endif\"".into()
+            })
+        );
+        assert_eq!(
+            super::assemble("ACC -> ACC\n.endif"),
+            Err(Error::Parse {
+                line: 2,
+                message: "\".endif\" without \".if\"".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_repeat() {
+        let text = "
+.rept 3, i
+    (i * 2 + 0x10) -> RAM.low
+.endr
+.rept 2
+wait:                       // Unique in each iteration
+    .rept COUNT
+    01 -> ACC.plus
+    .endr
+    lo@wait -> PC.latch : if_carry
+.endr
+after:
+    lo@after -> PC";
+        let options = Options {
+            defines: vec![("COUNT".to_string(), 2)].into_iter().collect(),
+            ..Default::default()
+        };
+        let assembly = assemble_for(text, &options).unwrap();
+        assert_eq!(
+            &assembly.bytes[1..7],
+            &[0b11_0110_00, 0x10, 0b11_0110_00, 0x12, 0b11_0110_00, 0x14]
+        );
+        assert_eq!(assembly.symbols["wait.0"], 0x0007);
+        assert_eq!(assembly.symbols["wait.1"], 0x000D);
+        assert_eq!(&assembly.bytes[0x0B..0x0D], &[0b11_0100_01, 0x07]);
+        assert_eq!(&assembly.bytes[0x11..0x13], &[0b11_0100_01, 0x0D]);
+        // Pass one sized the expanded blocks
+        assert_eq!(assembly.symbols["after"], 0x0013);
+        assert_eq!(assembly.bytes[0x14], 0x13);
+        assert_eq!(assembly.source_map.get(0x0D).unwrap().location.line, 8);
+
+        assert_eq!(
+            super::assemble(".rept 0\nnot code\n.endr"),
+            Ok(vec![0b01_0011_00])
+        );
+        assert_eq!(
+            super::assemble("\n.rept 2\nACC -> LED"),
+            Err(Error::Parse {
+                line: 2,
+                message: "Missing \".endr\"".into()
+            })
+        );
+        assert!(super::assemble(".endr").is_err());
+        assert!(super::assemble(".rept 2\n.if 1\n.endr\n.endif").is_err());
+
+        // Renamed labels can't clash with the source's own
+        let text = "wait_0:\n.rept 2\nwait:\nlo@wait -> PC\n.endr\nlo@wait_0 -> PC";
+        let assembly = assemble_for(text, &Options::default()).unwrap();
+        assert_eq!(assembly.symbols["wait_0"], 0x0001);
+        assert_eq!(assembly.symbols["wait.1"], 0x0003);
+        let object = assemble_object(text, &Options::default()).unwrap();
+        assert_eq!(object.to_string().parse::<Object>(), Ok(object));
+
+        // Nested blocks that would take forever to expand
+        assert_eq!(
+            super::assemble(".rept 0x10000\n.rept 0x10000\n.endr\n.endr"),
+            Err(Error::Parse {
+                line: 2,
+                message: "Repeats more than 65536 times in total".into()
+            })
+        );
+        let options = Options {
+            rom_size: Some(0x100),
+            ..Default::default()
+        };
+        assert_eq!(
+            assemble_for(".rept 0x20\n.rept 0x10\nACC -> ACC\n.endr\n.endr", &options),
+            Err(Error::Parse {
+                line: 2,
+                message: "Expands to more statements than fit in 256 bytes of ROM".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_char_literals() {
+        assert_eq!(
+            assemble("'\\n' -> Serial.out\n'\\x1B' -> Serial.out").unwrap(),
+            vec![0b01_0011_00, 0b11_1000_00, 0x0A, 0b11_1000_00, 0x1B]
+        );
+        assert_eq!(
+            assemble("'é' -> Serial.out"),
+            Err(Error::Parse {
+                line: 1,
+                message: "Non-ASCII character 'é', use a \"\\x\" escape or allow Latin-1".into()
+            })
+        );
+        let options = Options {
+            syntax: Syntax {
+                latin1: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let assembly = assemble_for("'é' -> Serial.out", &options).unwrap();
+        assert_eq!(assembly.bytes[2], 0xE9);
+    }
+
+    #[test]
+    fn test_string() {
+        let assembly = assemble_for(
+            "start:\n.string \"Hi\\n\"\nlo@start -> PC",
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0b01_0011_00,
+                0b11_1000_00,
+                b'H',
+                0b11_1000_00,
+                b'i',
+                0b11_1000_00,
+                b'\n',
+                0b11_0101_00,
+                0x01
+            ]
+        );
+        assert_eq!(
+            assembly.source_map.bytes[6].as_ref().unwrap().location.line,
+            2
+        );
+        assert_eq!(assembly.usage, vec![("text".to_string(), 9)]);
+
+        let object = assemble_object(".string \"ab\"", &Options::default()).unwrap();
+        assert_eq!(
+            object.sections[0].bytes,
+            vec![0b11_1000_00, b'a', 0b11_1000_00, b'b']
+        );
+    }
+
     #[test]
     #[should_panic]
     fn test_assembler_err() {
@@ -1,21 +1,146 @@
-use anyhow::{bail, Result};
-use assembler::assemble;
+use anyhow::{anyhow, bail, Context, Result};
+use assembler::conditional::parse_define;
+use assembler::image::{chips, ImageOptions, Layout};
+use assembler::{
+    assemble_for, assemble_object, format_source_map, format_symbols, format_usage, Options,
+    ADDRESS_SPACE,
+};
+use common::args::Args;
 use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 
-fn main() -> Result<()> {
-    let mut args = std::env::args().skip(1);
-    let (input_path, output_path) = if let (Some(i), Some(o)) = (args.next(), args.next()) {
-        (i, o)
+/// A size in bytes, in decimal, hex with `0x` or kibibytes with `K`, such as `32K`
+fn parse_size(s: &str) -> Result<usize> {
+    let size = if let Some(kib) = s.strip_suffix('K').or_else(|| s.strip_suffix('k')) {
+        kib.parse::<usize>().map(|kib| kib * 1024)
+    } else if let Some(hex) = s.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16)
     } else {
-        bail!("Usage: <input_path> <output_path>");
+        s.parse()
+    };
+    match size {
+        Ok(size) if size > 0 && size <= ADDRESS_SPACE => Ok(size),
+        _ => bail!("Invalid size {}, it can be at most 64K", s),
+    }
+}
+
+/// `rom.bin` with a suffix such as `0` becomes `rom.0.bin`
+fn chip_path(output_path: &str, suffix: Option<&str>) -> PathBuf {
+    let path = Path::new(output_path);
+    match (suffix, path.file_stem()) {
+        (Some(suffix), Some(stem)) => {
+            let mut name = stem.to_os_string();
+            name.push(".");
+            name.push(suffix);
+            if let Some(extension) = path.extension() {
+                name.push(".");
+                name.push(extension);
+            }
+            path.with_file_name(name)
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+fn main() -> Result<()> {
+    let mut args = Args::from_env();
+    let mut symbols_path = None;
+    let mut source_map_path = None;
+    let mut options = Options::default();
+    let mut image = ImageOptions::default();
+    let mut fill = None;
+    let mut object = false;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        if args.reset(&arg, &mut options.reset)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--symbols" => symbols_path = Some(args.value(&arg)?),
+            "--source-map" => source_map_path = Some(args.value(&arg)?),
+            "--rom-size" => options.rom_size = Some(parse_size(&args.value(&arg)?)?),
+            "--chip-size" => image.chip_size = Some(parse_size(&args.value(&arg)?)?),
+            "--fill" => {
+                let byte = args.value(&arg)?;
+                fill = Some(
+                    u8::from_str_radix(&byte, 16)
+                        .with_context(|| format!("--fill needs a hex byte, not \"{}\"", byte))?,
+                );
+            }
+            "--split" => image.layout = Layout::Split,
+            "--chips" => {
+                let count = args.parse::<NonZeroUsize>(&arg)?;
+                image.chips = Some(count.get());
+            }
+            "--interleave" => image.layout = Layout::Interleave,
+            "--object" => object = true,
+            "--strict" => options.syntax.strict = true,
+            "--latin1" => options.syntax.latin1 = true,
+            "-D" => {
+                let define = args.value(&arg)?;
+                let (name, value) = parse_define(&define).map_err(|e| anyhow!(e))?;
+                options.defines.insert(name, value);
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let (input_path, output_path) = match paths.as_slice() {
+        [i, o] => (i, o),
+        _ => bail!(
+            "Usage: <input_path> <output_path> [--symbols <symbols_path>] \
+            [--source-map <map_path>] [--reset-vector <hex>] [--no-dummy-nop] [--strict] \
+            [--latin1] [--rom-size <bytes>] [--chip-size <bytes>] [--fill <hex>] [--split] \
+            [--chips <count>] [--interleave] [--object] [-D <name>[=<value>]]..."
+        ),
     };
 
+    if let Some(fill) = fill {
+        if image.chip_size.is_none() {
+            bail!("--fill only pads chips, so it needs --chip-size");
+        }
+        image.fill = fill;
+    }
+    if image.chips.is_some() && image.layout != Layout::Split {
+        bail!("--chips limits how many chips --split uses, so it needs --split");
+    }
+
     let text = fs::read_to_string(input_path)?;
     if text == "" {
         bail!("Empty input file!");
     }
-    let bytecode = assemble(&text)?;
+    if object {
+        if symbols_path.is_some() {
+            bail!("--symbols comes from the linker when using --object");
+        }
+        if source_map_path.is_some() {
+            bail!("Objects don't record source lines, so --source-map can't be used with --object");
+        }
+        fs::write(output_path, assemble_object(&text, &options)?.to_string())?;
+        return Ok(());
+    }
+    let assembly = assemble_for(&text, &options)?;
+    for warning in &assembly.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    print!("{}", format_usage(&assembly, options.rom_size()));
 
-    fs::write(output_path, &bytecode)?;
+    for chip in chips(&assembly.bytes, &image)? {
+        let path = chip_path(output_path, chip.suffix.as_deref());
+        fs::write(&path, &chip.bytes)?;
+        println!(
+            "{} {:04X}-{:04X} checksum {:04X}",
+            path.display(),
+            chip.start,
+            chip.end,
+            chip.checksum()
+        );
+    }
+    if let Some(path) = symbols_path {
+        fs::write(path, format_symbols(&assembly.symbols))?;
+    }
+    if let Some(path) = source_map_path {
+        fs::write(path, format_source_map(&assembly.source_map, input_path))?;
+    }
     Ok(())
 }
@@ -0,0 +1,467 @@
+//! Relocatable object files, which the linker combines into a ROM image
+
+use crate::assembler::{AssemblerError, IntoInstruction, ADDRESS_SPACE};
+use crate::conditional::{compute, Defines};
+use crate::parser::{text_operations, Statement};
+use crate::repeat::is_symbol;
+use common::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Where code goes before the first `.section`
+pub const DEFAULT_SECTION: &str = "text";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// `lo@label`, the low byte of the address
+    Lo,
+    /// `hi@label`, the high byte of the address
+    Hi,
+}
+
+/// An operand byte the linker fills in once it knows where `symbol` ends up
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    /// Offset of the operand within its section
+    pub offset: u16,
+    pub kind: RelocationKind,
+    pub symbol: Label,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Section {
+    pub name: String,
+    /// Operands that need relocating are left as zero
+    pub bytes: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// A label defined in this object
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: Label,
+    pub section: String,
+    pub offset: u16,
+    /// Visible to other objects
+    pub global: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    /// Labels this object expects another one to define
+    pub externs: Vec<Label>,
+}
+
+impl Object {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// Whether a label used by `src` is defined here or declared `.extern`
+    fn symbol_or_extern(&self, src: &Source) -> bool {
+        match src {
+            Source::LabelLo(label) | Source::LabelHi(label) => {
+                self.symbol(label).is_some() || self.externs.contains(label)
+            }
+            _ => true,
+        }
+    }
+
+    fn section_mut(&mut self, name: &str) -> &mut Section {
+        match self
+            .sections
+            .iter()
+            .position(|section| section.name == name)
+        {
+            Some(index) => &mut self.sections[index],
+            None => {
+                self.sections.push(Section {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                self.sections.last_mut().unwrap()
+            }
+        }
+    }
+}
+
+/// Assemble statements into an object, leaving every `lo@`/`hi@` operand for the linker
+pub fn assemble(lines: &[(Statement, usize)]) -> Result<Object, AssemblerError> {
+    let mut object = Object::default();
+
+    // Pass one: find the offset of every label within its section
+    let mut offsets = HashMap::new();
+    let mut section = DEFAULT_SECTION;
+    for (statement, line) in lines {
+        match statement {
+            Statement::Section(name) => {
+                section = name.as_str();
+                object.section_mut(section);
+            }
+            Statement::Label(label) => {
+                object.section_mut(section);
+                let pc = *offsets.entry(section).or_insert(0);
+                if object.symbol(label).is_some() {
+                    Err(AssemblerError::RepeatLabel {
+                        label: label.clone(),
+                        line: *line,
+                    })?;
+                }
+                object.symbols.push(Symbol {
+                    name: label.clone(),
+                    section: section.to_string(),
+                    offset: pc as u16,
+                    global: false,
+                });
+            }
+            Statement::Operation(op) | Statement::Computed(op, _) => {
+                let pc = offsets.entry(section).or_insert(0);
+                match op.src {
+                    Source::Operand(_) | Source::LabelLo(_) | Source::LabelHi(_) => *pc += 2,
+                    _ => *pc += 1,
+                }
+                if *pc > ADDRESS_SPACE {
+                    Err(AssemblerError::TooLarge {
+                        size: ADDRESS_SPACE,
+                        line: *line,
+                    })?;
+                }
+            }
+            Statement::Text(text) => {
+                let pc = offsets.entry(section).or_insert(0);
+                *pc += 2 * text.len();
+                if *pc > ADDRESS_SPACE {
+                    Err(AssemblerError::TooLarge {
+                        size: ADDRESS_SPACE,
+                        line: *line,
+                    })?;
+                }
+            }
+            Statement::Origin(address) => {
+                let pc = offsets.entry(section).or_insert(0);
+                if (*address as usize) < *pc {
+                    Err(AssemblerError::OriginOverlap {
+                        address: *address,
+                        line: *line,
+                    })?;
+                }
+                *pc = *address as usize;
+            }
+            Statement::Extern(label) => {
+                if !object.externs.contains(label) {
+                    object.externs.push(label.clone());
+                }
+            }
+            Statement::Global(_) => (),
+        }
+    }
+
+    // Pass two: emit the bytecode and relocations
+    let mut section = DEFAULT_SECTION;
+    for (statement, line) in lines {
+        let unrecognized = |label: &Label| AssemblerError::UnrecognizedLabel {
+            label: label.clone(),
+            line: *line,
+        };
+        let generated;
+        let ops = match statement {
+            Statement::Operation(op) => std::slice::from_ref(op),
+            Statement::Computed(op, expression) => {
+                generated = vec![compute(op, expression, &Defines::new())];
+                &generated[..]
+            }
+            Statement::Text(text) => {
+                generated = text_operations(text);
+                &generated[..]
+            }
+            Statement::Section(name) => {
+                section = name.as_str();
+                continue;
+            }
+            Statement::Global(label) => {
+                let symbol = object
+                    .symbols
+                    .iter_mut()
+                    .find(|symbol| symbol.name == *label)
+                    .ok_or_else(|| unrecognized(label))?;
+                symbol.global = true;
+                continue;
+            }
+            Statement::Origin(address) => {
+                object
+                    .section_mut(section)
+                    .bytes
+                    .resize(*address as usize, NOP_BYTE);
+                continue;
+            }
+            Statement::Label(_) | Statement::Extern(_) => continue,
+        };
+        for op in ops {
+            let known = object.symbol_or_extern(&op.src);
+            let bytes = &mut object.section_mut(section).bytes;
+            bytes.push(op.instruction_bits());
+            let offset = bytes.len() as u16;
+            let (kind, label) = match &op.src {
+                Source::Operand(operand) => {
+                    bytes.push(*operand);
+                    (None, None)
+                }
+                Source::LabelLo(label) => (Some(RelocationKind::Lo), Some(label)),
+                Source::LabelHi(label) => (Some(RelocationKind::Hi), Some(label)),
+                _ => (None, None),
+            };
+            if let (Some(kind), Some(label)) = (kind, label) {
+                if !known {
+                    Err(unrecognized(label))?;
+                }
+                let section = object.section_mut(section);
+                section.bytes.push(0);
+                section.relocations.push(Relocation {
+                    offset,
+                    kind,
+                    symbol: label.clone(),
+                });
+            }
+            if matches!(
+                op.src,
+                Source::Operand(_) | Source::LabelLo(_) | Source::LabelHi(_)
+            ) && op.dest == Destination::Memory
+            {
+                Err(AssemblerError::ForbiddenInstruction { line: *line })?;
+            }
+        }
+    }
+    Ok(object)
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("Malformed object file on line {line}; {message}")]
+pub struct ObjectError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Object files are text, one record per line:
+///
+/// ```text
+/// section text
+/// bytes 0000 4C D0 00 D4 00
+/// reloc 0002 lo loop
+/// reloc 0004 hi loop
+/// symbol loop text 0001 global
+/// extern print
+/// ```
+///
+/// `bytes` and `reloc` belong to the section before them, and offsets are in hex.
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for section in &self.sections {
+            writeln!(f, "section {}", section.name)?;
+            for (i, chunk) in section.bytes.chunks(16).enumerate() {
+                write!(f, "bytes {:04X}", i * 16)?;
+                for byte in chunk {
+                    write!(f, " {:02X}", byte)?;
+                }
+                writeln!(f)?;
+            }
+            for relocation in &section.relocations {
+                let kind = match relocation.kind {
+                    RelocationKind::Lo => "lo",
+                    RelocationKind::Hi => "hi",
+                };
+                writeln!(
+                    f,
+                    "reloc {:04X} {} {}",
+                    relocation.offset, kind, relocation.symbol
+                )?;
+            }
+        }
+        for symbol in &self.symbols {
+            write!(
+                f,
+                "symbol {} {} {:04X}",
+                symbol.name, symbol.section, symbol.offset
+            )?;
+            if symbol.global {
+                write!(f, " global")?;
+            }
+            writeln!(f)?;
+        }
+        for label in &self.externs {
+            writeln!(f, "extern {}", label)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Object {
+    type Err = ObjectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut object = Object::default();
+        for (line_number, line) in s.lines().enumerate() {
+            let error = |message: &str| ObjectError {
+                line: line_number + 1,
+                message: message.to_string(),
+            };
+            let hex = |word: &str| {
+                u16::from_str_radix(word, 16).map_err(|_| error("Expected a hex offset"))
+            };
+            let name = |word: Option<&str>| match word {
+                Some(word) if is_symbol(word) => Ok(word.to_string()),
+                _ => Err(error("Expected a name")),
+            };
+            let mut words = line.split_whitespace();
+            let section = object.sections.last_mut();
+            match words.next() {
+                None => continue,
+                Some("section") => {
+                    let name = name(words.next())?;
+                    if object.sections.iter().any(|section| section.name == name) {
+                        return Err(error("Section appears twice"));
+                    }
+                    object.sections.push(Section {
+                        name,
+                        ..Default::default()
+                    });
+                }
+                Some("bytes") => {
+                    let section = section.ok_or_else(|| error("Bytes outside a section"))?;
+                    if hex(words.next().unwrap_or(""))? as usize != section.bytes.len() {
+                        return Err(error("Bytes out of order"));
+                    }
+                    for word in words.by_ref() {
+                        let byte = u8::from_str_radix(word, 16)
+                            .map_err(|_| error("Expected a hex byte"))?;
+                        section.bytes.push(byte);
+                    }
+                }
+                Some("reloc") => {
+                    let section = section.ok_or_else(|| error("Relocation outside a section"))?;
+                    let offset = hex(words.next().unwrap_or(""))?;
+                    let kind = match words.next() {
+                        Some("lo") => RelocationKind::Lo,
+                        Some("hi") => RelocationKind::Hi,
+                        _ => return Err(error("Expected lo or hi")),
+                    };
+                    if offset as usize >= section.bytes.len() {
+                        return Err(error("Relocation outside its section"));
+                    }
+                    section.relocations.push(Relocation {
+                        offset,
+                        kind,
+                        symbol: name(words.next())?,
+                    });
+                }
+                Some("symbol") => {
+                    let symbol = Symbol {
+                        name: name(words.next())?,
+                        section: name(words.next())?,
+                        offset: hex(words.next().unwrap_or(""))?,
+                        global: match words.next() {
+                            Some("global") => true,
+                            None => false,
+                            Some(_) => return Err(error("Expected global")),
+                        },
+                    };
+                    object.symbols.push(symbol);
+                }
+                Some("extern") => object.externs.push(name(words.next())?),
+                Some(_) => return Err(error("Unrecognized record")),
+            }
+            if words.next().is_some() {
+                return Err(error("Unexpected text at the end of the line"));
+            }
+        }
+        Ok(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn test_object() {
+        let text = "
+.extern print
+.global main
+main:
+loop:
+    'a' -> ACC
+    lo@print -> PC.latch
+    hi@print -> PC
+.section data
+table:
+    lo@loop -> ACC";
+        let object = assemble(&parse(text, &Default::default()).unwrap()).unwrap();
+        assert_eq!(object.sections.len(), 2);
+        let text_section = &object.sections[0];
+        assert_eq!(text_section.name, DEFAULT_SECTION);
+        assert_eq!(
+            text_section.bytes,
+            vec![0b11_0011_00, b'a', 0b11_0100_00, 0, 0b11_0101_00, 0]
+        );
+        assert_eq!(
+            text_section.relocations,
+            vec![
+                Relocation {
+                    offset: 3,
+                    kind: RelocationKind::Lo,
+                    symbol: "print".into()
+                },
+                Relocation {
+                    offset: 5,
+                    kind: RelocationKind::Hi,
+                    symbol: "print".into()
+                },
+            ]
+        );
+        assert_eq!(object.sections[1].relocations[0].offset, 1);
+        assert!(object.symbol("main").unwrap().global);
+        assert!(!object.symbol("loop").unwrap().global);
+        assert_eq!(object.symbol("table").unwrap().section, "data");
+        assert_eq!(object.externs, vec!["print".to_string()]);
+
+        assert_eq!(object.to_string().parse::<Object>(), Ok(object));
+    }
+
+    #[test]
+    fn test_object_errors() {
+        let object = |text| assemble(&parse(text, &Default::default()).unwrap());
+        assert_eq!(
+            object("lo@print -> PC"),
+            Err(AssemblerError::UnrecognizedLabel {
+                label: "print".into(),
+                line: 1
+            })
+        );
+        assert!(object(".global nowhere").is_err());
+        assert!(object("a:\n.section data\na:").is_err());
+
+        assert!("bytes 0000 00".parse::<Object>().is_err());
+        assert_eq!(
+            "section text\nsection data\nsection text".parse::<Object>(),
+            Err(ObjectError {
+                line: 3,
+                message: "Section appears twice".into()
+            })
+        );
+        assert!("section text\nbytes 0001 00".parse::<Object>().is_err());
+        assert!("section text\nbytes 0000 00\nreloc 0001 lo x"
+            .parse::<Object>()
+            .is_err());
+        assert_eq!(
+            "section text\nsymbol".parse::<Object>(),
+            Err(ObjectError {
+                line: 2,
+                message: "Expected a name".into()
+            })
+        );
+    }
+}
@@ -6,7 +6,7 @@ use nom::bytes::complete::*;
 use nom::character::complete::*;
 use nom::character::*;
 use nom::combinator::*;
-use nom::error::ErrorKind;
+use nom::error::{make_error, ErrorKind};
 use nom::multi::*;
 use nom::sequence::*;
 use nom::IResult;
@@ -16,10 +16,35 @@ use nom::{AsChar, InputTakeAtPosition};
 pub enum Statement {
     Label(Label),
     Operation(Operation),
+    /// `.org 0100`: continue assembling at this hex address, padding with NOPs
+    Origin(u16),
+    /// `.section name`: put what follows in the named section of an object file
+    Section(String),
+    /// `.global label`: let other object files use a label defined here
+    Global(Label),
+    /// `.extern label`: a label defined in another object file
+    Extern(Label),
+    /// An operation whose operand is the low byte of an expression, such as `(i * 2) -> RAM.low`.
+    /// The operand in the operation is a placeholder until `parse` evaluates the expression.
+    Computed(Operation, Expression),
+    /// `.string "Hi\n"`: send each byte of the text to `Serial.out`, see `text_operations`
+    Text(Vec<u8>),
+}
+
+/// The moves a `.string` sends its text with, one per byte
+pub fn text_operations(text: &[u8]) -> Vec<Operation> {
+    text.iter()
+        .map(|&byte| Operation {
+            src: Source::Operand(byte),
+            dest: Destination::Serial,
+            cond_1: false,
+            cond_carry: false,
+        })
+        .collect()
 }
 
 fn parse_hex(input: &str) -> IResult<&str, u8> {
-    map_res(take_while_m_n(2, 2, |c: char| c.is_digit(16)), |s| {
+    map_res(take_while_m_n(2, 2, |c: char| c.is_ascii_hexdigit()), |s| {
         u8::from_str_radix(s, 16)
     })(input)
 }
@@ -28,55 +53,89 @@ fn parse_name<'a>(s: &str) -> IResult<&str, &str> {
     s.split_at_position1_complete(|item| !item.is_alphanum() && item != '_', ErrorKind::NoneOf)
 }
 
-fn parse_char<'a>(s: &str) -> IResult<&str, u8> {
-    map(delimited(tag("'"), anychar, tag("'")), |c| {
-        let mut buf = [0];
-        c.encode_utf8(&mut buf);
-        buf[0]
-    })(s)
+fn parse_char(s: &str, latin1: bool) -> IResult<&str, u8> {
+    match parse_char_literal(s, latin1) {
+        Ok((byte, rest)) => Ok((rest, byte)),
+        Err(_) => Err(nom::Err::Error(make_error(s, ErrorKind::Char))),
+    }
+}
+
+fn parse_string(s: &str, latin1: bool) -> IResult<&str, Vec<u8>> {
+    match parse_string_literal(s, latin1) {
+        Ok((text, rest)) => Ok((rest, text)),
+        Err(_) => Err(nom::Err::Error(make_error(s, ErrorKind::Char))),
+    }
+}
+
+/// Variations on the syntax the parser accepts
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Syntax {
+    /// Only accept keywords spelt exactly as in the README, without aliases
+    pub strict: bool,
+    /// Character literals may hold U+0080 to U+00FF, standing for the byte of the same value
+    pub latin1: bool,
+}
+
+const CONDITIONS: [&str; 2] = ["if_carry", "if_1"];
+
+const DIRECTIVES: [&str; 12] = [
+    "section", "global", "extern", "org", "string", "if", "ifdef", "ifndef", "else", "endif",
+    "rept", "endr",
+];
+
+/// Match a keyword from `table`, as `find_keyword` does
+fn parse_keyword<'a, T: Clone + std::fmt::Display>(
+    s: &'a str,
+    table: &[(&str, T)],
+    strict: bool,
+) -> IResult<&'a str, T> {
+    let (rest, word) = take_while1(|c: char| c.is_alphanumeric() || c == '.' || c == '_')(s)?;
+    match find_keyword(table, word, strict) {
+        Some(value) => Ok((rest, value)),
+        None => Err(nom::Err::Error(make_error(s, ErrorKind::Tag))),
+    }
+}
+
+fn keyword_tag<'a>(
+    keyword: &'static str,
+    strict: bool,
+) -> impl Fn(&'a str) -> IResult<&'a str, &'a str> {
+    move |s| {
+        if strict {
+            tag(keyword)(s)
+        } else {
+            tag_no_case(keyword)(s)
+        }
+    }
 }
 
-fn parse_source(s: &str) -> IResult<&str, Source> {
+fn parse_source(s: &str, syntax: Syntax) -> IResult<&str, Source> {
+    let strict = syntax.strict;
     alt((
-        map(tag("EXP"), |_| Source::Expansion),
-        map(tag("ACC"), |_| Source::Accumulator),
-        map(tag("RAM"), |_| Source::Memory),
-        map(preceded(tag("lo@"), parse_name), |label| {
+        move |s| parse_keyword(s, &SOURCE_KEYWORDS, strict),
+        map(preceded(keyword_tag("lo@", strict), parse_name), |label| {
             Source::LabelLo(label.to_string())
         }),
-        map(preceded(tag("hi@"), parse_name), |label| {
+        map(preceded(keyword_tag("hi@", strict), parse_name), |label| {
             Source::LabelHi(label.to_string())
         }),
-        map(parse_char, Source::Operand),
+        map(|s| parse_char(s, syntax.latin1), Source::Operand),
         map(parse_hex, Source::Operand),
     ))(s)
 }
 
-fn parse_destination(s: &str) -> IResult<&str, Destination> {
-    alt((
-        map(tag("RAM.low"), |_| Destination::MemAddressLo),
-        map(tag("RAM.high"), |_| Destination::MemAddressHi),
-        map(tag("RAM"), |_| Destination::Memory),
-        map(tag("ACC.plus"), |_| Destination::AccumulatorPlus),
-        map(tag("ACC.nand"), |_| Destination::AccumulatorNand),
-        map(tag("ACC"), |_| Destination::Accumulator),
-        map(tag("PC.latch"), |_| Destination::ProgramCounterLatch),
-        map(tag("PC"), |_| Destination::ProgramCounter),
-        map(tag("LED"), |_| Destination::Led),
-        map(tag("carry.set"), |_| Destination::CarrySet),
-        map(tag("carry.reset"), |_| Destination::CarryReset),
-        map(tag("EXP.sel"), |_| Destination::ExpansionSelect),
-        map(tag("Serial.out"), |_| Destination::Serial),
-    ))(s)
+fn parse_destination(s: &str, strict: bool) -> IResult<&str, Destination> {
+    parse_keyword(s, &DESTINATION_KEYWORDS, strict)
 }
 
-fn parse_operation(s: &str) -> IResult<&str, Operation> {
+/// Everything after the source: the arrow, destination and conditions
+fn parse_move(s: &str, syntax: Syntax) -> IResult<&str, (Destination, bool, bool)> {
+    let strict = syntax.strict;
     let arrow = delimited(space1, tag("->"), space1);
     let colon = delimited(space1, tag(":"), space1);
     let bar = || delimited(space1, tag("|"), space1);
-    let mov = separated_pair(parse_source, arrow, parse_destination);
-    let one = || tag("if_1");
-    let carry = || tag("if_carry");
+    let one = || keyword_tag("if_1", strict);
+    let carry = || keyword_tag("if_carry", strict);
 
     let conditions = alt((
         map(separated_pair(carry(), bar(), one()), |_| (true, true)),
@@ -91,8 +150,21 @@ fn parse_operation(s: &str) -> IResult<&str, Operation> {
     ));
 
     map(
-        tuple((mov, conditions)),
-        |((src, dest), (cond_1, cond_carry))| Operation {
+        pair(
+            preceded(arrow, move |s| parse_destination(s, strict)),
+            conditions,
+        ),
+        |(dest, (cond_1, cond_carry))| (dest, cond_1, cond_carry),
+    )(s)
+}
+
+fn parse_operation(s: &str, syntax: Syntax) -> IResult<&str, Operation> {
+    map(
+        pair(
+            move |s| parse_source(s, syntax),
+            move |s| parse_move(s, syntax),
+        ),
+        |(src, (dest, cond_1, cond_carry))| Operation {
             src,
             dest,
             cond_1,
@@ -101,29 +173,388 @@ fn parse_operation(s: &str) -> IResult<&str, Operation> {
     )(s)
 }
 
+/// An operation whose operand is a bracketed expression
+fn parse_computed(s: &str, syntax: Syntax) -> IResult<&str, Statement> {
+    map(
+        pair(parse_bracketed, move |s| parse_move(s, syntax)),
+        |(expression, (dest, cond_1, cond_carry))| {
+            let op = Operation {
+                src: Source::Operand(0),
+                dest,
+                cond_1,
+                cond_carry,
+            };
+            Statement::Computed(op, expression)
+        },
+    )(s)
+}
+
 fn parse_label(s: &str) -> IResult<&str, Label> {
     map(terminated(parse_name, tag(":")), |s| s.to_string())(s)
 }
 
-fn parse_statement(s: &str) -> IResult<&str, Statement> {
+fn parse_directive(s: &str, syntax: Syntax) -> IResult<&str, Statement> {
+    let strict = syntax.strict;
+    let directive = |name| preceded(pair(keyword_tag(name, strict), space1), parse_name);
     alt((
-        map(parse_operation, Statement::Operation),
+        map(directive(".section"), |name| {
+            Statement::Section(name.to_string())
+        }),
+        map(directive(".global"), |label| {
+            Statement::Global(label.to_string())
+        }),
+        map(directive(".extern"), |label| {
+            Statement::Extern(label.to_string())
+        }),
+        map(
+            preceded(
+                pair(keyword_tag(".org", strict), space1),
+                map_res(take_while_m_n(1, 4, |c: char| c.is_ascii_hexdigit()), |s| {
+                    u16::from_str_radix(s, 16)
+                }),
+            ),
+            Statement::Origin,
+        ),
+        map(
+            preceded(pair(keyword_tag(".string", strict), space1), |s| {
+                parse_string(s, syntax.latin1)
+            }),
+            Statement::Text,
+        ),
+    ))(s)
+}
+
+/// Directives for conditional assembly, which decide which lines are assembled at all
+#[derive(Debug, Clone, PartialEq)]
+pub enum Conditional {
+    If(Expression),
+    IfDef(String),
+    IfNDef(String),
+    Else,
+    EndIf,
+}
+
+/// The condition of an `.if`. Symbols are defined outside the source, see `Options::defines`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(i64),
+    Symbol(String),
+    Not(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+fn parse_number(s: &str) -> IResult<&str, i64> {
+    alt((
+        map_res(preceded(tag_no_case("0x"), hex_digit1), |s| {
+            i64::from_str_radix(s, 16)
+        }),
+        map_res(digit1, |s: &str| s.parse()),
+    ))(s)
+}
+
+fn parse_term(s: &str) -> IResult<&str, Expression> {
+    preceded(
+        space0,
+        alt((
+            map(preceded(tag("!"), parse_term), |e| {
+                Expression::Not(Box::new(e))
+            }),
+            parse_bracketed,
+            map(parse_number, Expression::Number),
+            map(parse_name, |name| Expression::Symbol(name.to_string())),
+        )),
+    )(s)
+}
+
+fn parse_product(s: &str) -> IResult<&str, Expression> {
+    parse_chain(s, parse_term, &[("*", Operator::Multiply)])
+}
+
+fn parse_sum(s: &str) -> IResult<&str, Expression> {
+    parse_chain(
+        s,
+        parse_product,
+        &[("+", Operator::Add), ("-", Operator::Subtract)],
+    )
+}
+
+fn parse_comparison(s: &str) -> IResult<&str, Expression> {
+    let (s, left) = parse_sum(s)?;
+    let operator = alt((
+        map(tag("=="), |_| Operator::Equal),
+        map(tag("!="), |_| Operator::NotEqual),
+        map(tag("<="), |_| Operator::LessEqual),
+        map(tag(">="), |_| Operator::GreaterEqual),
+        map(tag("<"), |_| Operator::Less),
+        map(tag(">"), |_| Operator::Greater),
+    ));
+    let operator: IResult<&str, Operator> = preceded(space0, operator)(s);
+    match operator {
+        Ok((s, operator)) => {
+            let (s, right) = parse_sum(s)?;
+            Ok((
+                s,
+                Expression::Binary(Box::new(left), operator, Box::new(right)),
+            ))
+        }
+        Err(_) => Ok((s, left)),
+    }
+}
+
+/// `next` separated by any of `operators`, grouped from the left
+fn parse_chain<'a>(
+    s: &'a str,
+    next: fn(&str) -> IResult<&str, Expression>,
+    operators: &[(&'static str, Operator)],
+) -> IResult<&'a str, Expression> {
+    let (mut s, mut left) = next(s)?;
+    'chain: loop {
+        for (token, operator) in operators {
+            if let Ok((rest, right)) = preceded(pair(space0, tag(*token)), next)(s) {
+                left = Expression::Binary(Box::new(left), *operator, Box::new(right));
+                s = rest;
+                continue 'chain;
+            }
+        }
+        return Ok((s, left));
+    }
+}
+
+fn parse_and(s: &str) -> IResult<&str, Expression> {
+    parse_chain(s, parse_comparison, &[("&&", Operator::And)])
+}
+
+/// A C-like expression of numbers and symbols with `+`, `-`, `*`, `!`, comparisons, `&&`, `||`
+/// and brackets
+pub fn parse_expression(s: &str) -> IResult<&str, Expression> {
+    parse_chain(s, parse_and, &[("||", Operator::Or)])
+}
+
+/// An expression in brackets, as used for computed operands
+pub fn parse_bracketed(s: &str) -> IResult<&str, Expression> {
+    delimited(tag("("), parse_expression, preceded(space0, tag(")")))(s)
+}
+
+/// Parse a line holding a conditional directive, leaving any trailing comment
+pub fn parse_conditional(s: &str, syntax: Syntax) -> IResult<&str, Conditional> {
+    let strict = syntax.strict;
+    let named = |directive| preceded(pair(keyword_tag(directive, strict), space1), parse_name);
+    terminated(
+        preceded(
+            space0,
+            alt((
+                map(named(".ifdef"), |name| Conditional::IfDef(name.to_string())),
+                map(named(".ifndef"), |name| {
+                    Conditional::IfNDef(name.to_string())
+                }),
+                map(
+                    preceded(pair(keyword_tag(".if", strict), space1), parse_expression),
+                    Conditional::If,
+                ),
+                map(keyword_tag(".else", strict), |_| Conditional::Else),
+                map(keyword_tag(".endif", strict), |_| Conditional::EndIf),
+            )),
+        ),
+        peek(alt((preceded(space0, tag("//")), all_consuming(space0)))),
+    )(s)
+}
+
+/// `.rept count[, counter]` and `.endr`, which repeat the lines between them
+#[derive(Debug, Clone, PartialEq)]
+pub enum Repeat {
+    Start {
+        count: Expression,
+        /// Symbol holding the iteration, counting from 0
+        counter: Option<String>,
+    },
+    End,
+}
+
+/// Parse a line holding `.rept` or `.endr`, leaving any trailing comment
+pub fn parse_repeat(s: &str, syntax: Syntax) -> IResult<&str, Repeat> {
+    let strict = syntax.strict;
+    let counter = preceded(tuple((space0, tag(","), space0)), parse_name);
+    let start = preceded(
+        pair(keyword_tag(".rept", strict), space1),
+        pair(parse_expression, opt(counter)),
+    );
+    terminated(
+        preceded(
+            space0,
+            alt((
+                map(start, |(count, counter)| Repeat::Start {
+                    count,
+                    counter: counter.map(str::to_string),
+                }),
+                map(keyword_tag(".endr", strict), |_| Repeat::End),
+            )),
+        ),
+        peek(alt((preceded(space0, tag("//")), all_consuming(space0)))),
+    )(s)
+}
+
+fn parse_statement(s: &str, syntax: Syntax) -> IResult<&str, Statement> {
+    alt((
+        move |s| parse_directive(s, syntax),
+        map(|s| parse_operation(s, syntax), Statement::Operation),
+        move |s| parse_computed(s, syntax),
         map(parse_label, Statement::Label),
     ))(s)
 }
 
-pub fn parse_line(s: &str) -> IResult<&str, Option<Statement>> {
+/// Parse one line of source, leaving any trailing comment
+pub fn parse_line(s: &str, syntax: Syntax) -> IResult<&str, Option<Statement>> {
     alt((
-        map(preceded(space0, parse_statement), Some),
-        map(tag("//"), |_| None),
+        map(
+            terminated(
+                preceded(space0, |s| parse_statement(s, syntax)),
+                peek(alt((preceded(space0, tag("//")), all_consuming(space0)))),
+            ),
+            Some,
+        ),
+        map(preceded(space0, tag("//")), |_| None),
         map(tag("%"), |_| None),
         map(all_consuming(space0), |_| None),
     ))(s)
 }
 
+/// Explain why a line doesn't parse, suggesting the closest keyword if one looks misspelt
+pub fn explain_error(s: &str, syntax: Syntax) -> String {
+    let strict = syntax.strict;
+    let code = s.split("//").next().unwrap_or("").trim();
+    if let Some(directive) = code.strip_prefix('.') {
+        let name = directive.split_whitespace().next().unwrap_or("");
+        return match DIRECTIVES.iter().find(|d| d.eq_ignore_ascii_case(name)) {
+            Some(d) if strict && *d != name => {
+                format!(
+                    "Unrecognized directive \".{}\", did you mean \".{}\"?",
+                    name, d
+                )
+            }
+            Some(&"if") => {
+                "Expected a condition such as \"BOARD_REV == 2\" after \".if\"".to_string()
+            }
+            Some(&"org") => "Expected a hex address such as \"0100\" after \".org\"".to_string(),
+            Some(&"string") => {
+                // Taken from the whole line, since the string may contain "//"
+                let literal = s.trim_start()[1 + name.len()..].trim_start();
+                match parse_string_literal(literal, syntax.latin1) {
+                    Ok((_, rest)) => format!("Unexpected \"{}\" after the string", rest.trim()),
+                    Err(e) if literal.starts_with('"') => e.message,
+                    Err(_) => "Expected a string in double quotes after \".string\"".to_string(),
+                }
+            }
+            Some(&"rept") => {
+                "Expected a count such as \"8\" or \"8, i\" after \".rept\"".to_string()
+            }
+            Some(d @ &"else") | Some(d @ &"endif") | Some(d @ &"endr") => {
+                format!("Unexpected text after \".{}\"", d)
+            }
+            Some(d) => format!("Expected a name after \".{}\"", d),
+            None => format!("Unrecognized directive \".{}\"", name),
+        };
+    }
+    let (src, rest) = match code.find("->") {
+        Some(i) => (code[..i].trim(), &code[i + 2..]),
+        None if is_label(code) => return format!("Expected \":\" after the label \"{}\"", code),
+        None => return format!("Expected a label or an instruction, found \"{}\"", code),
+    };
+    let (dest, conditions) = match rest.find(':') {
+        Some(i) => (rest[..i].trim(), Some(&rest[i + 1..])),
+        None => (rest.trim(), None),
+    };
+
+    if src.starts_with('\'') {
+        if let Err(e) = parse_char_literal(src, syntax.latin1) {
+            return e.message;
+        }
+    }
+    if src.starts_with('(') {
+        if all_consuming(parse_bracketed)(src).is_err() {
+            return "Expected an expression in brackets such as \"(i * 2)\"".to_string();
+        }
+    } else if all_consuming(|s| parse_source(s, syntax))(src).is_err() {
+        return unrecognized("source", src, &SOURCE_KEYWORDS);
+    }
+    if all_consuming(|s| parse_destination(s, strict))(dest).is_err() {
+        return unrecognized("destination", dest, &DESTINATION_KEYWORDS);
+    }
+    let conditions = conditions.map_or_else(Vec::new, |c| c.split('|').map(str::trim).collect());
+    for condition in conditions {
+        let known = CONDITIONS
+            .iter()
+            .any(|c| *c == condition || !strict && c.eq_ignore_ascii_case(condition));
+        if !known {
+            let table = CONDITIONS.iter().map(|c| (*c, *c)).collect::<Vec<_>>();
+            return unrecognized("condition", condition, &table);
+        }
+    }
+    "Expected single spaces or tabs around \"->\", \":\" and \"|\"".to_string()
+}
+
+fn unrecognized<T: std::fmt::Display>(kind: &str, word: &str, table: &[(&str, T)]) -> String {
+    if word.is_empty() {
+        return format!("Missing {}", kind);
+    }
+    let word_lower = word.to_ascii_lowercase();
+    let closest = table
+        .iter()
+        .map(|(name, value)| {
+            (
+                edit_distance(&word_lower, &name.to_ascii_lowercase()),
+                name,
+                value,
+            )
+        })
+        .filter(|(distance, name, _)| *distance <= name.len() / 2)
+        .min_by_key(|(distance, _, _)| *distance);
+    match closest {
+        Some((_, _, value)) => format!(
+            "Unrecognized {} \"{}\", did you mean \"{}\"?",
+            kind, word, value
+        ),
+        None => format!("Unrecognized {} \"{}\"", kind, word),
+    }
+}
+
+/// Number of single character insertions, deletions and substitutions to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitute.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    const STRICT: Syntax = Syntax {
+        strict: true,
+        latin1: false,
+    };
     #[test]
     fn test_parse_name() {
         assert!(all_consuming(parse_name)("this_is_an_outrage!").is_err());
@@ -135,89 +566,126 @@ mod tests {
 
     #[test]
     fn test_parse_char() {
-        assert!(parse_char("'").is_err());
-        assert!(parse_char("'a").is_err());
-        assert!(parse_char("a'").is_err());
-        assert!(parse_char("a").is_err());
-        assert!(parse_char("").is_err());
-        assert_eq!(parse_char("'a'"), Ok(("", 0x61)));
-        assert_eq!(parse_char("'a'thisi sa buncha JUNK"), Ok(("thisi sa buncha JUNK", 0x61)));
+        assert!(parse_char("'", false).is_err());
+        assert!(parse_char("'a", false).is_err());
+        assert!(parse_char("a'", false).is_err());
+        assert!(parse_char("a", false).is_err());
+        assert!(parse_char("", false).is_err());
+        assert_eq!(parse_char("'a'", false), Ok(("", 0x61)));
+        assert_eq!(
+            parse_char("'a'thisi sa buncha JUNK", false),
+            Ok(("thisi sa buncha JUNK", 0x61))
+        );
+        assert_eq!(parse_char("'\\n'", false), Ok(("", 0x0A)));
+        assert_eq!(parse_char("'\\x1B' -> ACC", false), Ok((" -> ACC", 0x1B)));
+        assert!(parse_char("'é'", false).is_err());
+        assert_eq!(parse_char("'é'", true), Ok(("", 0xE9)));
     }
 
     #[test]
     fn test_parse_source() {
-        assert!(parse_source("").is_err());
-        assert!(parse_source("0").is_err());
-        assert_eq!(parse_source("EXP"), Ok(("", Source::Expansion)));
-        assert_eq!(parse_source("ACC"), Ok(("", Source::Accumulator)));
-        assert_eq!(parse_source("RAM"), Ok(("", Source::Memory)));
-        assert_eq!(parse_source("00"), Ok(("", Source::Operand(0x00))));
-        assert_eq!(
-            parse_source("lo@my_label"),
+        assert!(parse_source("", Syntax::default()).is_err());
+        assert!(parse_source("0", Syntax::default()).is_err());
+        assert_eq!(
+            parse_source("EXP", Syntax::default()),
+            Ok(("", Source::Expansion))
+        );
+        assert_eq!(
+            parse_source("ACC", Syntax::default()),
+            Ok(("", Source::Accumulator))
+        );
+        assert_eq!(
+            parse_source("RAM", Syntax::default()),
+            Ok(("", Source::Memory))
+        );
+        assert_eq!(
+            parse_source("00", Syntax::default()),
+            Ok(("", Source::Operand(0x00)))
+        );
+        assert_eq!(
+            parse_source("lo@my_label", Syntax::default()),
             Ok(("", Source::LabelLo("my_label".into())))
         );
         assert_eq!(
-            parse_source("hi@my_label"),
+            parse_source("hi@my_label", Syntax::default()),
             Ok(("", Source::LabelHi("my_label".into())))
         );
-        assert_eq!(parse_source("5F"), Ok(("", Source::Operand(0x5F))));
+        assert_eq!(
+            parse_source("5F", Syntax::default()),
+            Ok(("", Source::Operand(0x5F)))
+        );
     }
 
     #[test]
     fn test_parse_destination() {
-        assert_eq!(parse_destination("RAM"), Ok(("", Destination::Memory)));
         assert_eq!(
-            parse_destination("RAM.low"),
+            parse_destination("RAM", false),
+            Ok(("", Destination::Memory))
+        );
+        assert_eq!(
+            parse_destination("RAM.low", false),
             Ok(("", Destination::MemAddressLo))
         );
         assert_eq!(
-            parse_destination("RAM.high"),
+            parse_destination("RAM.high", false),
             Ok(("", Destination::MemAddressHi))
         );
-        assert_eq!(parse_destination("ACC"), Ok(("", Destination::Accumulator)));
         assert_eq!(
-            parse_destination("ACC.plus"),
+            parse_destination("ACC", false),
+            Ok(("", Destination::Accumulator))
+        );
+        assert_eq!(
+            parse_destination("ACC.plus", false),
             Ok(("", Destination::AccumulatorPlus))
         );
         assert_eq!(
-            parse_destination("ACC.nand"),
+            parse_destination("ACC.nand", false),
             Ok(("", Destination::AccumulatorNand))
         );
         assert_eq!(
-            parse_destination("PC"),
+            parse_destination("PC", false),
             Ok(("", Destination::ProgramCounter))
         );
         assert_eq!(
-            parse_destination("PC.latch"),
+            parse_destination("PC.latch", false),
             Ok(("", Destination::ProgramCounterLatch))
         );
-        assert_eq!(parse_destination("LED"), Ok(("", Destination::Led)));
+        assert_eq!(parse_destination("LED", false), Ok(("", Destination::Led)));
         assert_eq!(
-            parse_destination("carry.set"),
+            parse_destination("carry.set", false),
             Ok(("", Destination::CarrySet))
         );
         assert_eq!(
-            parse_destination("carry.reset"),
+            parse_destination("carry.reset", false),
             Ok(("", Destination::CarryReset))
         );
         assert_eq!(
-            parse_destination("EXP.sel"),
+            parse_destination("EXP.sel", false),
             Ok(("", Destination::ExpansionSelect))
         );
         assert_eq!(
-            parse_destination("Serial.out"),
+            parse_destination("Serial.out", false),
             Ok(("", Destination::Serial))
         );
+        assert_eq!(
+            parse_destination("spare.D", false),
+            Ok(("", Destination::Spare(SpareDestination::D)))
+        );
+        assert_eq!(
+            parse_destination("spare.F", false),
+            Ok(("", Destination::Spare(SpareDestination::F)))
+        );
+        assert!(parse_destination("spare.C", false).is_err());
     }
 
     #[test]
     fn test_parse_operation() {
-        assert!(parse_operation("-> RAM").is_err());
-        assert!(parse_operation("RAM -> : if_carry").is_err());
-        assert!(parse_operation("-> : ").is_err());
-        assert!(parse_operation("RAM.low -> RAM").is_err());
+        assert!(parse_operation("-> RAM", Syntax::default()).is_err());
+        assert!(parse_operation("RAM -> : if_carry", Syntax::default()).is_err());
+        assert!(parse_operation("-> : ", Syntax::default()).is_err());
+        assert!(parse_operation("RAM.low -> RAM", Syntax::default()).is_err());
         assert_eq!(
-            parse_operation("5F -> ACC // Comment"),
+            parse_operation("5F -> ACC // Comment", Syntax::default()),
             Ok((
                 " // Comment",
                 Operation {
@@ -229,7 +697,7 @@ mod tests {
             ))
         );
         assert_eq!(
-            parse_operation("'q' -> RAM // Comment"),
+            parse_operation("'q' -> RAM // Comment", Syntax::default()),
             Ok((
                 " // Comment",
                 Operation {
@@ -241,7 +709,7 @@ mod tests {
             ))
         );
         assert_eq!(
-            parse_operation("RAM -> RAM : if_carry // Comment"),
+            parse_operation("RAM -> RAM : if_carry // Comment", Syntax::default()),
             Ok((
                 " // Comment",
                 Operation {
@@ -253,7 +721,10 @@ mod tests {
             ))
         );
         assert_eq!(
-            parse_operation("lo@some_label -> ACC.nand : if_carry | if_1 // Comment"),
+            parse_operation(
+                "lo@some_label -> ACC.nand : if_carry | if_1 // Comment",
+                Syntax::default()
+            ),
             Ok((
                 " // Comment",
                 Operation {
@@ -279,7 +750,7 @@ mod tests {
     #[test]
     fn test_parse_statement() {
         assert_eq!(
-            parse_statement("ACC -> ACC"),
+            parse_statement("ACC -> ACC", Syntax::default()),
             Ok((
                 "",
                 Statement::Operation(Operation {
@@ -291,7 +762,7 @@ mod tests {
             ))
         );
         assert_eq!(
-            parse_statement("thisisalabel:"),
+            parse_statement("thisisalabel:", Syntax::default()),
             Ok(("", Statement::Label("thisisalabel".into())))
         );
     }
@@ -299,7 +770,7 @@ mod tests {
     #[test]
     fn test_parse_line() {
         assert_eq!(
-            parse_line("\tRAM -> RAM : if_carry // Comment"),
+            parse_line("\tRAM -> RAM : if_carry // Comment", Syntax::default()),
             Ok((
                 " // Comment",
                 Some(Statement::Operation(Operation {
@@ -311,15 +782,287 @@ mod tests {
             ))
         );
         assert_eq!(
-            parse_line("    this_is_a_label:"),
+            parse_line("    this_is_a_label:", Syntax::default()),
             Ok(("", Some(Statement::Label("this_is_a_label".into()))))
         );
-        assert_eq!(parse_line("//"), Ok(("", None)));
+        assert_eq!(parse_line("//", Syntax::default()), Ok(("", None)));
         assert_eq!(
-            parse_line("// This is a comment"),
+            parse_line("\t// Indented", Syntax::default()),
+            Ok((" Indented", None))
+        );
+        assert_eq!(
+            parse_line("// This is a comment", Syntax::default()),
             Ok((" This is a comment", None))
         );
-        assert_eq!(parse_line(""), Ok(("", None)));
-        assert_eq!(parse_line("\t\t     "), Ok(("", None)));
+        assert_eq!(parse_line("", Syntax::default()), Ok(("", None)));
+        assert_eq!(parse_line("\t\t     ", Syntax::default()), Ok(("", None)));
+    }
+
+    #[test]
+    fn test_keywords() {
+        let op = |src, dest, cond_1, cond_carry| Operation {
+            src,
+            dest,
+            cond_1,
+            cond_carry,
+        };
+        assert_eq!(
+            parse_operation("acc -> Carry.Reset : IF_1", Syntax::default()),
+            Ok((
+                "",
+                op(Source::Accumulator, Destination::CarryReset, true, false)
+            ))
+        );
+        assert_eq!(
+            parse_operation("mem -> OUT", Syntax::default()),
+            Ok(("", op(Source::Memory, Destination::Serial, false, false)))
+        );
+        assert_eq!(
+            parse_operation("LO@loop -> add", Syntax::default()),
+            Ok((
+                "",
+                op(
+                    Source::LabelLo("loop".into()),
+                    Destination::AccumulatorPlus,
+                    false,
+                    false
+                )
+            ))
+        );
+        assert_eq!(
+            parse_operation("ACC -> Serial.out : if_carry", STRICT),
+            Ok((
+                "",
+                op(Source::Accumulator, Destination::Serial, false, true)
+            ))
+        );
+        assert!(parse_operation("acc -> ACC", STRICT).is_err());
+        assert!(parse_operation("MEM -> ACC", STRICT).is_err());
+        assert!(parse_operation("ACC -> SERIAL.OUT", STRICT).is_err());
+        assert!(parse_operation("LO@x -> PC", STRICT).is_err());
+        assert!(parse_line("ACC -> ACCfoo", Syntax::default()).is_err());
+        assert!(parse_line("ACC -> ACC : IF_1", STRICT).is_err());
+        assert!(parse_line("ACC -> ACC : if_cary", Syntax::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_directive() {
+        assert_eq!(
+            parse_line(".section lib // Library routines", Syntax::default()),
+            Ok((
+                " // Library routines",
+                Some(Statement::Section("lib".into()))
+            ))
+        );
+        assert_eq!(
+            parse_line("  .global multiply", Syntax::default()),
+            Ok(("", Some(Statement::Global("multiply".into()))))
+        );
+        assert_eq!(
+            parse_line(".EXTERN print", Syntax::default()),
+            Ok(("", Some(Statement::Extern("print".into()))))
+        );
+        assert!(parse_line(".EXTERN print", STRICT).is_err());
+        assert!(parse_line(".extern", Syntax::default()).is_err());
+        assert!(parse_line(".extern a b", Syntax::default()).is_err());
+        assert_eq!(
+            parse_line(".org 0100", Syntax::default()),
+            Ok(("", Some(Statement::Origin(0x0100))))
+        );
+        assert!(parse_line(".org 10000", Syntax::default()).is_err());
+        assert_eq!(
+            parse_line(".string \"a//b\\r\\n\" // Banner", Syntax::default()),
+            Ok((" // Banner", Some(Statement::Text(b"a//b\r\n".to_vec()))))
+        );
+        assert!(parse_line(".string \"é\"", Syntax::default()).is_err());
+        assert_eq!(
+            explain_error(".string \"\\e\"", Syntax::default()),
+            "Unknown escape \"\\e\" in string literal"
+        );
+        assert_eq!(
+            explain_error(".string Hi", Syntax::default()),
+            "Expected a string in double quotes after \".string\""
+        );
+        assert_eq!(
+            explain_error(".string \"Hi\" there", Syntax::default()),
+            "Unexpected \"there\" after the string"
+        );
+        assert_eq!(
+            explain_error(".org main", Syntax::default()),
+            "Expected a hex address such as \"0100\" after \".org\""
+        );
+        assert_eq!(
+            explain_error(".section", Syntax::default()),
+            "Expected a name after \".section\""
+        );
+        assert_eq!(
+            explain_error(".sektion text", Syntax::default()),
+            "Unrecognized directive \".sektion\""
+        );
+    }
+
+    #[test]
+    fn test_parse_conditional() {
+        let symbol = |name: &str| Box::new(Expression::Symbol(name.into()));
+        let number = |n| Box::new(Expression::Number(n));
+        assert_eq!(
+            parse_conditional(".if BOARD_REV >= 2 // New LEDs", Syntax::default()),
+            Ok((
+                " // New LEDs",
+                Conditional::If(Expression::Binary(
+                    symbol("BOARD_REV"),
+                    Operator::GreaterEqual,
+                    number(2)
+                ))
+            ))
+        );
+        assert_eq!(
+            parse_conditional(".if !SERIAL || (REV==0x1 && LEDS)", Syntax::default()),
+            Ok((
+                "",
+                Conditional::If(Expression::Binary(
+                    Box::new(Expression::Not(symbol("SERIAL"))),
+                    Operator::Or,
+                    Box::new(Expression::Binary(
+                        Box::new(Expression::Binary(
+                            symbol("REV"),
+                            Operator::Equal,
+                            number(1)
+                        )),
+                        Operator::And,
+                        symbol("LEDS")
+                    ))
+                ))
+            ))
+        );
+        assert_eq!(
+            parse_conditional("  .ifdef SERIAL", Syntax::default()),
+            Ok(("", Conditional::IfDef("SERIAL".into())))
+        );
+        assert_eq!(
+            parse_conditional(".ifndef SERIAL", Syntax::default()),
+            Ok(("", Conditional::IfNDef("SERIAL".into())))
+        );
+        assert_eq!(
+            parse_conditional(".ELSE", Syntax::default()),
+            Ok(("", Conditional::Else))
+        );
+        assert_eq!(
+            parse_conditional(".endif // SERIAL", Syntax::default()),
+            Ok((" // SERIAL", Conditional::EndIf))
+        );
+        assert!(parse_conditional(".ELSE", STRICT).is_err());
+        assert!(parse_conditional(".if", Syntax::default()).is_err());
+        assert!(parse_conditional(".if (A", Syntax::default()).is_err());
+        assert!(parse_conditional(".if A B", Syntax::default()).is_err());
+        assert!(parse_conditional(".elsewhere", Syntax::default()).is_err());
+        assert!(parse_conditional("ACC -> ACC", Syntax::default()).is_err());
+        assert_eq!(
+            explain_error(".if", Syntax::default()),
+            "Expected a condition such as \"BOARD_REV == 2\" after \".if\""
+        );
+    }
+
+    #[test]
+    fn test_parse_repeat() {
+        assert_eq!(
+            parse_repeat(".rept 4 // Unrolled", Syntax::default()),
+            Ok((
+                " // Unrolled",
+                Repeat::Start {
+                    count: Expression::Number(4),
+                    counter: None
+                }
+            ))
+        );
+        assert_eq!(
+            parse_repeat("  .rept DELAY * 2, i", Syntax::default()),
+            Ok((
+                "",
+                Repeat::Start {
+                    count: Expression::Binary(
+                        Box::new(Expression::Symbol("DELAY".into())),
+                        Operator::Multiply,
+                        Box::new(Expression::Number(2))
+                    ),
+                    counter: Some("i".into())
+                }
+            ))
+        );
+        assert_eq!(
+            parse_repeat(".endr", Syntax::default()),
+            Ok(("", Repeat::End))
+        );
+        assert!(parse_repeat(".rept", Syntax::default()).is_err());
+        assert!(parse_repeat(".rept 4,", Syntax::default()).is_err());
+        assert!(parse_repeat(".endr 4", Syntax::default()).is_err());
+
+        assert_eq!(
+            parse_line("(i * 2 + 0x10) -> RAM.low : if_1", Syntax::default()),
+            Ok((
+                "",
+                Some(Statement::Computed(
+                    Operation {
+                        src: Source::Operand(0),
+                        dest: Destination::MemAddressLo,
+                        cond_1: true,
+                        cond_carry: false,
+                    },
+                    Expression::Binary(
+                        Box::new(Expression::Binary(
+                            Box::new(Expression::Symbol("i".into())),
+                            Operator::Multiply,
+                            Box::new(Expression::Number(2))
+                        )),
+                        Operator::Add,
+                        Box::new(Expression::Number(0x10))
+                    )
+                ))
+            ))
+        );
+        assert_eq!(
+            explain_error("(i * ) -> ACC", Syntax::default()),
+            "Expected an expression in brackets such as \"(i * 2)\""
+        );
+    }
+
+    #[test]
+    fn test_explain_error() {
+        assert_eq!(
+            explain_error("ACC -> SERIAL", Syntax::default()),
+            "Unrecognized destination \"SERIAL\", did you mean \"Serial.out\"?"
+        );
+        assert_eq!(
+            explain_error("acc -> ACC", STRICT),
+            "Unrecognized source \"acc\", did you mean \"ACC\"?"
+        );
+        assert_eq!(
+            explain_error("ACC -> OUT // Print", STRICT),
+            "Unrecognized destination \"OUT\", did you mean \"Serial.out\"?"
+        );
+        assert_eq!(
+            explain_error("ACC -> LED : if_cary", Syntax::default()),
+            "Unrecognized condition \"if_cary\", did you mean \"if_carry\"?"
+        );
+        assert_eq!(
+            explain_error("ACC -> xyzzy", Syntax::default()),
+            "Unrecognized destination \"xyzzy\""
+        );
+        assert_eq!(
+            explain_error("ACC ->", Syntax::default()),
+            "Missing destination"
+        );
+        assert_eq!(
+            explain_error("loop", Syntax::default()),
+            "Expected \":\" after the label \"loop\""
+        );
+        assert_eq!(
+            explain_error("ACC->LED", Syntax::default()),
+            "Expected single spaces or tabs around \"->\", \":\" and \"|\""
+        );
+        assert_eq!(
+            explain_error("'\\e' -> Serial.out", Syntax::default()),
+            "Unknown escape \"\\e\" in character literal"
+        );
     }
 }
@@ -0,0 +1,59 @@
+//! Helpers for expanding `.rept` blocks
+
+use crate::parser::{parse_repeat, Repeat, Statement, Syntax};
+use common::*;
+use std::collections::HashSet;
+
+/// Whether `name` is a label, possibly renamed by `rename_labels`
+pub fn is_symbol(name: &str) -> bool {
+    let mut parts = name.split('.');
+    parts.next().is_some_and(is_label)
+        && parts
+            .all(|iteration| !iteration.is_empty() && iteration.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Index of the `.endr` that closes a block whose lines start at `lines[0]`
+pub fn find_end(lines: &[(usize, &str)], syntax: Syntax) -> Option<usize> {
+    let mut depth = 0;
+    for (index, (_, line)) in lines.iter().enumerate() {
+        match parse_repeat(line, syntax) {
+            Ok((_, Repeat::Start { .. })) => depth += 1,
+            Ok((_, Repeat::End)) if depth == 0 => return Some(index),
+            Ok((_, Repeat::End)) => depth -= 1,
+            Err(_) => (),
+        }
+    }
+    None
+}
+
+/// Give the labels defined in one iteration of a block, and the references to them, the
+/// suffix `.<iteration>`. Labels can't contain `.`, so the result can't clash with one in the
+/// source.
+pub fn rename_labels(statements: &mut [(Statement, usize)], iteration: i64) {
+    let defined = statements
+        .iter()
+        .filter_map(|(statement, _)| match statement {
+            Statement::Label(label) => Some(label.clone()),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    let rename = |label: &mut Label| {
+        if defined.contains(label) {
+            *label = format!("{}.{}", label, iteration);
+        }
+    };
+    for (statement, _) in statements {
+        match statement {
+            Statement::Label(label) | Statement::Global(label) => rename(label),
+            Statement::Operation(Operation {
+                src: Source::LabelLo(label),
+                ..
+            })
+            | Statement::Operation(Operation {
+                src: Source::LabelHi(label),
+                ..
+            }) => rename(label),
+            _ => (),
+        }
+    }
+}
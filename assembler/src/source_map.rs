@@ -0,0 +1,167 @@
+use std::fmt;
+
+/// Where a line of the assembler's input originally came from. `file` is `None` for lines of the
+/// input itself.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Location {
+    pub file: Option<String>,
+    pub line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteKind {
+    Opcode,
+    Operand,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappedByte {
+    pub location: Location,
+    pub kind: ByteKind,
+}
+
+/// The source of every emitted byte, indexed by address. Padding and instructions generated by
+/// the assembler itself have no source.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SourceMap {
+    pub bytes: Vec<Option<MappedByte>>,
+}
+
+impl SourceMap {
+    pub fn get(&self, address: u16) -> Option<&MappedByte> {
+        self.bytes.get(address as usize).and_then(Option::as_ref)
+    }
+
+    /// Replace input line numbers with the locations recorded in `lines`
+    pub fn remap(&mut self, lines: &Lines) {
+        for byte in self.bytes.iter_mut().flatten() {
+            if byte.location.file.is_none() {
+                byte.location = lines.get(byte.location.line);
+            }
+        }
+    }
+}
+
+/// The original location of every line of the input. Preprocessors such as `nasm -E` mark the
+/// output of includes and macros with `%line <line>[+<step>] [file]` directives; the lines after
+/// one come from `file`, starting at `line` and advancing by `step` (1 by default) each line.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Lines {
+    locations: Vec<Location>,
+}
+
+impl Lines {
+    pub fn new(text: &str) -> Self {
+        let mut locations = Vec::new();
+        let mut current: Option<(Option<String>, usize, usize)> = None;
+        for (line_number, line) in text.lines().enumerate() {
+            if let Some((line, step, file)) = parse_line_directive(line) {
+                let file = file.or_else(|| current.take().and_then(|(file, _, _)| file));
+                current = Some((file, line, step));
+                locations.push(Location {
+                    file: None,
+                    line: line_number + 1,
+                });
+                continue;
+            }
+            match &mut current {
+                Some((file, line, step)) => {
+                    locations.push(Location {
+                        file: file.clone(),
+                        line: *line,
+                    });
+                    *line += *step;
+                }
+                None => locations.push(Location {
+                    file: None,
+                    line: line_number + 1,
+                }),
+            }
+        }
+        Self { locations }
+    }
+
+    /// Location of the 1-based input line `line`
+    pub fn get(&self, line: usize) -> Location {
+        match line.checked_sub(1).and_then(|i| self.locations.get(i)) {
+            Some(location) => location.clone(),
+            None => Location { file: None, line },
+        }
+    }
+}
+
+/// Returns (line, step, file) for a `%line` directive
+fn parse_line_directive(s: &str) -> Option<(usize, usize, Option<String>)> {
+    let rest = s.trim_start().strip_prefix("%line")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim();
+    let (numbers, file) = match rest.find(char::is_whitespace) {
+        Some(i) => (
+            &rest[..i],
+            Some(rest[i..].trim().trim_matches('"').to_string()),
+        ),
+        None => (rest, None),
+    };
+    let (line, step) = match numbers.find('+') {
+        Some(i) => (numbers[..i].parse().ok()?, numbers[i + 1..].parse().ok()?),
+        None => (numbers.parse().ok()?, 1),
+    };
+    Some((line, step, file.filter(|f| !f.is_empty())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines() {
+        // Roughly what `nasm -E` produces for a file that includes another and uses a macro
+        let text = "\
+%line 1+1 main.s
+ACC -> ACC
+%line 1+1 inc.s
+5F -> LED
+00 -> ACC
+%line 3+0 main.s
+01 -> ACC.plus
+ACC -> LED
+%line 5+1
+lo@loop -> PC";
+        let lines = Lines::new(text);
+        let location = |file: &str, line| Location {
+            file: Some(file.into()),
+            line,
+        };
+        assert_eq!(lines.get(2), location("main.s", 1));
+        assert_eq!(lines.get(4), location("inc.s", 1));
+        assert_eq!(lines.get(5), location("inc.s", 2));
+        assert_eq!(lines.get(7), location("main.s", 3));
+        assert_eq!(lines.get(8), location("main.s", 3));
+        assert_eq!(lines.get(10), location("main.s", 5));
+        assert_eq!(
+            lines.get(11),
+            Location {
+                file: None,
+                line: 11
+            }
+        );
+        assert_eq!(
+            Lines::new("ACC -> ACC\n%linefoo").get(2),
+            Location {
+                file: None,
+                line: 2
+            }
+        );
+    }
+}
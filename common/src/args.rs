@@ -0,0 +1,117 @@
+//! Helpers for the command line parsers of the binaries, which are written by hand
+
+use crate::Reset;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ArgError {
+    pub message: String,
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ArgError {}
+
+/// Command line arguments, read one at a time
+pub struct Args<I> {
+    args: I,
+}
+
+impl Args<std::iter::Skip<std::env::Args>> {
+    /// The arguments the program was started with, without its own name
+    pub fn from_env() -> Self {
+        Self::new(std::env::args().skip(1))
+    }
+}
+
+impl<I: Iterator<Item = String>> Args<I> {
+    pub fn new(args: I) -> Self {
+        Self { args }
+    }
+
+    /// The value following `option`
+    pub fn value(&mut self, option: &str) -> Result<String, ArgError> {
+        self.args.next().ok_or_else(|| ArgError {
+            message: format!("{} needs a value", option),
+        })
+    }
+
+    /// The value following `option`, parsed
+    pub fn parse<T: FromStr>(&mut self, option: &str) -> Result<T, ArgError> {
+        let value = self.value(option)?;
+        value.parse().map_err(|_| ArgError {
+            message: format!("Invalid value \"{}\" for {}", value, option),
+        })
+    }
+
+    /// The hex value following `option`
+    pub fn hex(&mut self, option: &str) -> Result<u16, ArgError> {
+        let value = self.value(option)?;
+        u16::from_str_radix(&value, 16).map_err(|_| ArgError {
+            message: format!("{} needs a hex value, not \"{}\"", option, value),
+        })
+    }
+
+    /// Apply `option` to `reset` if it's `--reset-vector <hex>` or `--no-dummy-nop`, returning
+    /// whether it was
+    pub fn reset(&mut self, option: &str, reset: &mut Reset) -> Result<bool, ArgError> {
+        match option {
+            "--reset-vector" => reset.vector = self.hex(option)?,
+            "--no-dummy-nop" => reset.skip_first_fetch = false,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+impl<I: Iterator<Item = String>> Iterator for Args<I> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.args.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU64;
+
+    fn arguments(args: &[&str]) -> Args<impl Iterator<Item = String>> {
+        Args::new(
+            args.iter()
+                .map(|arg| arg.to_string())
+                .collect::<Vec<_>>()
+                .into_iter(),
+        )
+    }
+
+    #[test]
+    fn test_args() {
+        let mut reset = Reset::default();
+        let mut args = arguments(&["--reset-vector", "0100", "--no-dummy-nop", "--steps", "x"]);
+        while let Some(arg) = args.next() {
+            if !args.reset(&arg, &mut reset).unwrap() {
+                assert_eq!(arg, "--steps");
+                assert_eq!(
+                    args.parse::<u64>(&arg).unwrap_err().to_string(),
+                    "Invalid value \"x\" for --steps"
+                );
+            }
+        }
+        assert_eq!(reset.vector, 0x0100);
+        assert!(!reset.skip_first_fetch);
+
+        assert!(arguments(&["0"]).parse::<NonZeroU64>("--clock").is_err());
+        assert!(arguments(&["wxyz"]).hex("--reset-vector").is_err());
+        assert_eq!(
+            arguments(&[]).value("-o").unwrap_err().to_string(),
+            "-o needs a value"
+        );
+    }
+}
@@ -1,3 +1,10 @@
+pub mod args;
+mod syntax;
+pub use syntax::{
+    find_keyword, is_label, parse_char_literal, parse_string_literal, ParseError,
+    DESTINATION_KEYWORDS, SOURCE_KEYWORDS,
+};
+
 pub type Label = String;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -10,7 +17,7 @@ pub enum Source {
     LabelHi(Label),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Destination {
     Memory,
     MemAddressLo,
@@ -25,6 +32,27 @@ pub enum Destination {
     CarryReset,
     ExpansionSelect,
     Serial,
+    /// One of the unassigned encodings 1101-1111
+    Spare(SpareDestination),
+}
+
+/// The unassigned destination encodings, named after their destination bits
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SpareDestination {
+    D,
+    E,
+    F,
+}
+
+impl SpareDestination {
+    /// The destination bits
+    pub fn code(self) -> u8 {
+        match self {
+            SpareDestination::D => 0b1101,
+            SpareDestination::E => 0b1110,
+            SpareDestination::F => 0b1111,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -34,3 +62,58 @@ pub struct Operation {
     pub cond_1: bool,
     pub cond_carry: bool,
 }
+
+/// `ACC -> ACC`, which does nothing
+pub const NOP: Operation = Operation {
+    src: Source::Accumulator,
+    dest: Destination::Accumulator,
+    cond_1: false,
+    cond_carry: false,
+};
+
+/// The encoding of `NOP`: source `ACC`, destination `ACC` and no conditions. Gaps in the ROM are
+/// filled with it so that running into one does nothing.
+pub const NOP_BYTE: u8 = (0b01 << 6) | (0b0011 << 2);
+
+/// How the board comes out of reset. Both the assembler and the emulator follow this, so that
+/// hardware revisions with different reset logic can be modelled.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Reset {
+    /// Address of the first instruction
+    pub vector: u16,
+    pub acc: u8,
+    pub pc_latch: u8,
+    pub mem_address: u16,
+    pub led: u8,
+    pub flag_1: bool,
+    pub flag_carry: bool,
+    /// The first fetch after reset is thrown away, so the program has to start with a dummy NOP
+    pub skip_first_fetch: bool,
+}
+
+impl Default for Reset {
+    /// The current board: everything starts at zero and the first fetch is skipped
+    fn default() -> Self {
+        Self {
+            vector: 0x0000,
+            acc: 0x00,
+            pc_latch: 0x00,
+            mem_address: 0x0000,
+            led: 0x00,
+            flag_1: false,
+            flag_carry: false,
+            skip_first_fetch: true,
+        }
+    }
+}
+
+impl Reset {
+    /// Address where the program's own code starts, after the dummy NOP if there is one
+    pub fn entry_point(&self) -> u16 {
+        if self.skip_first_fetch {
+            self.vector.wrapping_add(1)
+        } else {
+            self.vector
+        }
+    }
+}
@@ -0,0 +1,478 @@
+//! Textual syntax for instructions, as described in the README
+
+use crate::*;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Labels can contain a-z, A-Z, 0-9 and _
+pub fn is_label(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parse a character literal such as `'a'`, `'\n'` or `'\x1B'` from the start of `s`, returning
+/// its byte and the rest of `s`. The escapes are `\n`, `\r`, `\t`, `\0`, `\\`, `\'`, `\"` and
+/// `\xHH`. Characters outside ASCII are rejected unless `latin1` is set, in which case
+/// U+0080 to U+00FF stand for the byte of the same value.
+pub fn parse_char_literal(s: &str, latin1: bool) -> Result<(u8, &str), ParseError> {
+    let rest = s
+        .strip_prefix('\'')
+        .ok_or_else(|| ParseError::new("Expected a character literal"))?;
+    if rest.starts_with('\'') {
+        return Err(ParseError::new("Empty character literal"));
+    }
+    let mut chars = rest.chars();
+    let byte = parse_char(&mut chars, latin1, "character literal")?;
+    match chars.as_str().strip_prefix('\'') {
+        Some(rest) => Ok((byte, rest)),
+        None => Err(ParseError::new(
+            "Expected \"'\" after one character in a character literal",
+        )),
+    }
+}
+
+/// Parse a string literal such as `"Hello\r\n"` from the start of `s`, returning its bytes and
+/// the rest of `s`. Escapes and characters outside ASCII are treated as in `parse_char_literal`.
+pub fn parse_string_literal(s: &str, latin1: bool) -> Result<(Vec<u8>, &str), ParseError> {
+    let rest = s
+        .strip_prefix('"')
+        .ok_or_else(|| ParseError::new("Expected a string literal"))?;
+    let mut chars = rest.chars();
+    let mut bytes = Vec::new();
+    loop {
+        if let Some(rest) = chars.as_str().strip_prefix('"') {
+            return Ok((bytes, rest));
+        }
+        bytes.push(parse_char(&mut chars, latin1, "string literal")?);
+    }
+}
+
+/// Parse one character of a literal, which may be an escape. `kind` names the literal in errors.
+fn parse_char(chars: &mut std::str::Chars, latin1: bool, kind: &str) -> Result<u8, ParseError> {
+    Ok(match chars.next() {
+        None => return Err(ParseError::new(format!("Unterminated {}", kind))),
+        Some('\\') => match chars.next() {
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('\'') => b'\'',
+            Some('"') => b'"',
+            Some('x') => {
+                let digits = chars.as_str().get(..2).unwrap_or("");
+                if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(ParseError::new("Expected two hex digits after \"\\x\""));
+                }
+                chars.nth(1);
+                u8::from_str_radix(digits, 16).unwrap()
+            }
+            Some(c) => {
+                return Err(ParseError::new(format!(
+                    "Unknown escape \"\\{}\" in {}",
+                    c, kind
+                )))
+            }
+            None => return Err(ParseError::new(format!("Unterminated {}", kind))),
+        },
+        Some(c) if c.is_ascii() => c as u8,
+        Some(c) if latin1 && (c as u32) <= 0xFF => c as u32 as u8,
+        Some(c) if latin1 => {
+            return Err(ParseError::new(format!(
+                "Character '{}' is outside Latin-1, use a \"\\x\" escape",
+                c
+            )))
+        }
+        Some(c) => {
+            return Err(ParseError::new(format!(
+                "Non-ASCII character '{}', use a \"\\x\" escape or allow Latin-1",
+                c
+            )))
+        }
+    })
+}
+
+/// Source keywords. The canonical spelling of each is its `Display`; anything else is an alias.
+pub const SOURCE_KEYWORDS: [(&str, Source); 4] = [
+    ("EXP", Source::Expansion),
+    ("ACC", Source::Accumulator),
+    ("RAM", Source::Memory),
+    ("MEM", Source::Memory),
+];
+
+/// Destination keywords. The canonical spelling of each is its `Display`; anything else is an
+/// alias.
+pub const DESTINATION_KEYWORDS: [(&str, Destination); 20] = [
+    ("RAM", Destination::Memory),
+    ("MEM", Destination::Memory),
+    ("ACC.plus", Destination::AccumulatorPlus),
+    ("ADD", Destination::AccumulatorPlus),
+    ("ACC.nand", Destination::AccumulatorNand),
+    ("NAND", Destination::AccumulatorNand),
+    ("ACC", Destination::Accumulator),
+    ("PC.latch", Destination::ProgramCounterLatch),
+    ("PC", Destination::ProgramCounter),
+    ("RAM.low", Destination::MemAddressLo),
+    ("RAM.high", Destination::MemAddressHi),
+    ("Serial.out", Destination::Serial),
+    ("OUT", Destination::Serial),
+    ("LED", Destination::Led),
+    ("carry.set", Destination::CarrySet),
+    ("carry.reset", Destination::CarryReset),
+    ("EXP.sel", Destination::ExpansionSelect),
+    ("spare.D", Destination::Spare(SpareDestination::D)),
+    ("spare.E", Destination::Spare(SpareDestination::E)),
+    ("spare.F", Destination::Spare(SpareDestination::F)),
+];
+
+/// Find `word` in a keyword table. Keywords are case-insensitive and may be aliases, unless
+/// `strict` is set, in which case only the exact canonical spelling is accepted.
+pub fn find_keyword<T: Clone + fmt::Display>(
+    table: &[(&str, T)],
+    word: &str,
+    strict: bool,
+) -> Option<T> {
+    table
+        .iter()
+        .find(|(name, value)| {
+            if strict {
+                *name == word && value.to_string() == word
+            } else {
+                name.eq_ignore_ascii_case(word)
+            }
+        })
+        .map(|(_, value)| value.clone())
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Expansion => f.write_str("EXP"),
+            Source::Accumulator => f.write_str("ACC"),
+            Source::Memory => f.write_str("RAM"),
+            Source::Operand(value) => write!(f, "{:02X}", value),
+            Source::LabelLo(label) => write!(f, "lo@{}", label),
+            Source::LabelHi(label) => write!(f, "hi@{}", label),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let label = |label: &str| {
+            if is_label(label) {
+                Ok(label.to_string())
+            } else {
+                Err(ParseError::new(format!("Invalid label \"{}\"", label)))
+            }
+        };
+        // Like the assembler, keywords are case-insensitive and may be aliases
+        let prefix = |prefix: &str| s.get(..3).is_some_and(|p| p.eq_ignore_ascii_case(prefix));
+        if let Some(source) = find_keyword(&SOURCE_KEYWORDS, s, false) {
+            return Ok(source);
+        }
+        match s {
+            _ if prefix("lo@") => Ok(Source::LabelLo(label(&s[3..])?)),
+            _ if prefix("hi@") => Ok(Source::LabelHi(label(&s[3..])?)),
+            _ if s.starts_with('\'') => match parse_char_literal(s, false)? {
+                (byte, "") => Ok(Source::Operand(byte)),
+                (_, rest) => Err(ParseError::new(format!("Unexpected \"{}\"", rest))),
+            },
+            _ if s.len() == 2 && s.chars().all(|c| c.is_ascii_hexdigit()) => {
+                Ok(Source::Operand(u8::from_str_radix(s, 16).unwrap()))
+            }
+            _ => Err(ParseError::new(format!("Unrecognized source \"{}\"", s))),
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Destination::Memory => "RAM",
+            Destination::AccumulatorPlus => "ACC.plus",
+            Destination::AccumulatorNand => "ACC.nand",
+            Destination::Accumulator => "ACC",
+            Destination::ProgramCounterLatch => "PC.latch",
+            Destination::ProgramCounter => "PC",
+            Destination::MemAddressLo => "RAM.low",
+            Destination::MemAddressHi => "RAM.high",
+            Destination::Serial => "Serial.out",
+            Destination::Led => "LED",
+            Destination::CarrySet => "carry.set",
+            Destination::CarryReset => "carry.reset",
+            Destination::ExpansionSelect => "EXP.sel",
+            Destination::Spare(spare) => return write!(f, "spare.{:X}", spare.code()),
+        })
+    }
+}
+
+impl FromStr for Destination {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        find_keyword(&DESTINATION_KEYWORDS, s, false)
+            .ok_or_else(|| ParseError::new(format!("Unrecognized destination \"{}\"", s)))
+    }
+}
+
+impl fmt::Display for Operation {
+    /// Conditions are written in the canonical order `if_carry | if_1`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {}", self.src, self.dest)?;
+        match (self.cond_carry, self.cond_1) {
+            (true, true) => f.write_str(" : if_carry | if_1"),
+            (true, false) => f.write_str(" : if_carry"),
+            (false, true) => f.write_str(" : if_1"),
+            (false, false) => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = ParseError;
+
+    /// Parses a single move such as `55 -> RAM.low : if_carry | if_1`. Tokens are separated by
+    /// spaces or tabs, and comments are not allowed.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const SPACE: [char; 2] = [' ', '\t'];
+        let s = s.trim_matches(&SPACE[..]);
+        // A character literal may itself be a space, so split it off before tokenizing
+        let (src, rest) = if s.starts_with('\'') {
+            let (_, rest) = parse_char_literal(s, false)?;
+            s.split_at(s.len() - rest.len())
+        } else {
+            let end = s.find(&SPACE[..]).unwrap_or(s.len());
+            s.split_at(end)
+        };
+        let src = src.parse()?;
+
+        let mut tokens = rest.split(&SPACE[..]).filter(|t| !t.is_empty());
+        if !rest.starts_with(&SPACE[..]) || tokens.next() != Some("->") {
+            return Err(ParseError::new("Expected \"->\" after the source"));
+        }
+        let dest = tokens
+            .next()
+            .ok_or_else(|| ParseError::new("Missing destination"))?
+            .parse()?;
+
+        let mut op = Operation {
+            src,
+            dest,
+            cond_1: false,
+            cond_carry: false,
+        };
+        match tokens.next() {
+            None => return Ok(op),
+            Some(":") => (),
+            Some(t) => return Err(ParseError::new(format!("Unexpected \"{}\"", t))),
+        }
+        loop {
+            match tokens.next() {
+                Some(t) if t.eq_ignore_ascii_case("if_1") && !op.cond_1 => op.cond_1 = true,
+                Some(t) if t.eq_ignore_ascii_case("if_carry") && !op.cond_carry => {
+                    op.cond_carry = true
+                }
+                Some(t) => return Err(ParseError::new(format!("Unexpected condition \"{}\"", t))),
+                None => return Err(ParseError::new("Missing condition")),
+            }
+            match tokens.next() {
+                Some("|") => continue,
+                Some(t) => return Err(ParseError::new(format!("Unexpected \"{}\"", t))),
+                None => return Ok(op),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_format() {
+        let op = Operation {
+            src: Source::Operand(0x55),
+            dest: Destination::MemAddressLo,
+            cond_1: true,
+            cond_carry: true,
+        };
+        assert_eq!(op.to_string(), "55 -> RAM.low : if_carry | if_1");
+        assert_eq!(NOP.to_string(), "ACC -> ACC");
+        assert_eq!(Source::LabelHi("x".into()).to_string(), "hi@x");
+        assert_eq!(
+            Destination::Spare(SpareDestination::E).to_string(),
+            "spare.E"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "5f -> ACC.plus".parse(),
+            Ok(Operation {
+                src: Source::Operand(0x5F),
+                dest: Destination::AccumulatorPlus,
+                cond_1: false,
+                cond_carry: false,
+            })
+        );
+        assert_eq!(
+            "' ' -> Serial.out\t:\tif_1 | if_carry".parse(),
+            Ok(Operation {
+                src: Source::Operand(b' '),
+                dest: Destination::Serial,
+                cond_1: true,
+                cond_carry: true,
+            })
+        );
+        assert_eq!(
+            "lo@im_a_label -> PC.latch"
+                .parse::<Operation>()
+                .unwrap()
+                .src,
+            Source::LabelLo("im_a_label".into())
+        );
+        // The same keywords and aliases as the assembler
+        assert_eq!("add".parse(), Ok(Destination::AccumulatorPlus));
+        assert_eq!(
+            "mem -> OUT : IF_1".parse(),
+            Ok(Operation {
+                src: Source::Memory,
+                dest: Destination::Serial,
+                cond_1: true,
+                cond_carry: false,
+            })
+        );
+        assert_eq!("HI@start".parse(), Ok(Source::LabelHi("start".into())));
+        assert!("ACC->ACC".parse::<Operation>().is_err());
+        assert!("ACC -> ACC :".parse::<Operation>().is_err());
+        assert!("ACC -> ACC : if_1 |".parse::<Operation>().is_err());
+        assert!("ACC -> ACC : if_1 | if_1".parse::<Operation>().is_err());
+        assert!("ACC -> ACC // Comment".parse::<Operation>().is_err());
+        assert!("lo@bad-label -> PC".parse::<Operation>().is_err());
+        assert!("5 -> ACC".parse::<Operation>().is_err());
+        assert!("ACC -> spare.C".parse::<Operation>().is_err());
+        assert_eq!(
+            "'\\'' -> Serial.out".parse::<Operation>().unwrap().src,
+            Source::Operand(b'\'')
+        );
+    }
+
+    #[test]
+    fn test_char_literal() {
+        assert_eq!(
+            parse_char_literal("'a' -> ACC", false),
+            Ok((b'a', " -> ACC"))
+        );
+        assert_eq!(parse_char_literal("' '", false), Ok((b' ', "")));
+        assert_eq!(parse_char_literal("'\\n'", false), Ok((b'\n', "")));
+        assert_eq!(parse_char_literal("'\\''", false), Ok((b'\'', "")));
+        assert_eq!(parse_char_literal("'\\\\'", false), Ok((b'\\', "")));
+        assert_eq!(parse_char_literal("'\\x1b'", false), Ok((0x1B, "")));
+        assert_eq!(parse_char_literal("'\\0'", false), Ok((0, "")));
+        assert_eq!(parse_char_literal("'é'", true), Ok((0xE9, "")));
+        for bad in &["'é'", "''", "'a", "'ab'", "'\\q'", "'\\x4'", "'\\'", "a"] {
+            assert!(parse_char_literal(bad, false).is_err(), "{}", bad);
+        }
+        assert!(parse_char_literal("'€'", true).is_err());
+        assert_eq!(
+            parse_char_literal("'é'", false).unwrap_err().message,
+            "Non-ASCII character 'é', use a \"\\x\" escape or allow Latin-1"
+        );
+    }
+
+    #[test]
+    fn test_string_literal() {
+        assert_eq!(
+            parse_string_literal("\"Hi\\r\\n\" // greeting", false),
+            Ok((b"Hi\r\n".to_vec(), " // greeting"))
+        );
+        assert_eq!(
+            parse_string_literal("\"'\\\"\\x00\"", false),
+            Ok((b"'\"\0".to_vec(), ""))
+        );
+        assert_eq!(parse_string_literal("\"\"", false), Ok((Vec::new(), "")));
+        assert_eq!(parse_string_literal("\"é\"", true), Ok((vec![0xE9], "")));
+        for bad in &["\"é\"", "\"abc", "\"\\q\"", "\"\\\"", "abc"] {
+            assert!(parse_string_literal(bad, false).is_err(), "{}", bad);
+        }
+        assert_eq!(
+            parse_string_literal("\"a\\q\"", false).unwrap_err().message,
+            "Unknown escape \"\\q\" in string literal"
+        );
+    }
+
+    fn source() -> impl Strategy<Value = Source> {
+        prop_oneof![
+            Just(Source::Expansion),
+            Just(Source::Accumulator),
+            Just(Source::Memory),
+            any::<u8>().prop_map(Source::Operand),
+            "[a-zA-Z0-9_]{1,16}".prop_map(Source::LabelLo),
+            "[a-zA-Z0-9_]{1,16}".prop_map(Source::LabelHi),
+        ]
+    }
+
+    fn destination() -> impl Strategy<Value = Destination> {
+        prop_oneof![
+            Just(Destination::Memory),
+            Just(Destination::MemAddressLo),
+            Just(Destination::MemAddressHi),
+            Just(Destination::Accumulator),
+            Just(Destination::AccumulatorPlus),
+            Just(Destination::AccumulatorNand),
+            Just(Destination::ProgramCounter),
+            Just(Destination::ProgramCounterLatch),
+            Just(Destination::Led),
+            Just(Destination::CarrySet),
+            Just(Destination::CarryReset),
+            Just(Destination::ExpansionSelect),
+            Just(Destination::Serial),
+            Just(Destination::Spare(SpareDestination::D)),
+            Just(Destination::Spare(SpareDestination::E)),
+            Just(Destination::Spare(SpareDestination::F)),
+        ]
+    }
+
+    fn operation() -> impl Strategy<Value = Operation> {
+        (source(), destination(), any::<bool>(), any::<bool>()).prop_map(
+            |(src, dest, cond_1, cond_carry)| Operation {
+                src,
+                dest,
+                cond_1,
+                cond_carry,
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn test_roundtrip(op in operation()) {
+            prop_assert_eq!(op.to_string().parse::<Operation>(), Ok(op));
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use emulator::Emulator;
+
+const STEPS: usize = 10_000;
+
+/// Increments a counter in RAM forever, with a delay loop between each increment
+const PROGRAM: &str = "
+00 -> RAM.high
+00 -> RAM.low
+main_loop:
+00 -> ACC
+ACC -> carry.reset
+delay_loop:
+01 -> ACC.plus
+lo@out_of_loop -> PC : if_carry
+lo@delay_loop -> PC
+out_of_loop:
+RAM -> ACC
+ACC -> LED
+ACC -> carry.reset
+01 -> ACC.plus
+ACC -> RAM
+lo@main_loop -> PC";
+
+fn run(emulator: &mut Emulator) {
+    for _ in 0..STEPS {
+        emulator.step().unwrap();
+    }
+}
+
+/// Runs through a long stretch of code without branching, then jumps back to the start
+fn straight_line() -> String {
+    let mut text = String::from("start:\n");
+    for i in 0..STEPS {
+        text += match i % 4 {
+            0 => "01 -> ACC.plus\n",
+            1 => "ACC -> LED\n",
+            2 => "ACC -> RAM\n",
+            _ => "RAM -> ACC.nand\n",
+        };
+    }
+    text + "hi@start -> PC.latch\nlo@start -> PC\n"
+}
+
+fn bench_program(c: &mut Criterion, name: &str, text: &str) {
+    let program = assembler::assemble(text).unwrap().into_boxed_slice();
+    let mut group = c.benchmark_group(name);
+
+    let mut decoded = Emulator::from_program(program.clone()).unwrap();
+    group.bench_function("decoder", |b| b.iter(|| run(&mut decoded)));
+
+    let mut predecoded = Emulator::from_program(program).unwrap();
+    predecoded.predecode();
+    group.bench_function("predecoded", |b| b.iter(|| run(&mut predecoded)));
+
+    group.finish();
+}
+
+fn bench_step(c: &mut Criterion) {
+    bench_program(c, "delay_loop", PROGRAM);
+    bench_program(c, "straight_line", &straight_line());
+}
+
+criterion_group!(benches, bench_step);
+criterion_main!(benches);
@@ -0,0 +1,51 @@
+use anyhow::{bail, Result};
+use common::args::Args;
+use common::Reset;
+use emulator::difftest::{compare, parse_trace};
+use emulator::expansion::SpareDestinations;
+use emulator::Emulator;
+use std::fs;
+
+const USAGE: &str = "Usage: <file_name.bin> <trace.txt> [--reset-vector <hex>] [--no-dummy-nop] \
+    [--spare-destinations <error|nop>]";
+
+fn main() -> Result<()> {
+    let mut args = Args::from_env();
+    let mut paths = Vec::new();
+    let mut reset = Reset::default();
+    let mut spare_destinations = SpareDestinations::default();
+    while let Some(arg) = args.next() {
+        if args.reset(&arg, &mut reset)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--spare-destinations" => spare_destinations = args.parse(&arg)?,
+            _ if paths.len() < 2 => paths.push(arg),
+            _ => bail!(USAGE),
+        }
+    }
+    if spare_destinations == SpareDestinations::Expansion {
+        bail!("difftest has no expansion bus to hand spare destinations to");
+    }
+    let (program_path, trace_path) = match paths.as_slice() {
+        [program, trace] => (program, trace),
+        _ => bail!(USAGE),
+    };
+
+    let trace = parse_trace(&fs::read_to_string(trace_path)?)?;
+    let program = fs::read(program_path)?;
+    let mut emulator = Emulator::with_reset(program.into_boxed_slice(), reset)?;
+    emulator.spare_destinations = spare_destinations;
+
+    match compare(&mut emulator, &trace) {
+        Ok(summary) => println!(
+            "Matched {} lines over {} cycles, {} of them in the middle of an instruction",
+            summary.lines, summary.cycles, summary.mid_instruction
+        ),
+        Err(divergence) => {
+            print!("{}", divergence);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
@@ -1,60 +1,76 @@
+use crate::predecode::{Fetch, MicroOp};
 use common::*;
 use thiserror::Error;
 
 #[derive(Debug, Clone, Copy, Error)]
 pub enum DecoderError {
-    #[error("Unrecognized destination")]
-    UnrecognizedDestination,
     #[error("Tried to read an instruction off the end of the data segment")]
     InvalidRead,
 }
 
-pub trait FromByte: Sized {
-    fn from_byte(byte: u8) -> Result<Self, DecoderError>;
+pub trait FromByte {
+    fn from_byte(byte: u8) -> Self;
 }
 
+/// Destinations by the four bits in the middle of an opcode
+const DESTINATIONS: [Destination; 16] = [
+    Destination::Memory,
+    Destination::AccumulatorPlus,
+    Destination::AccumulatorNand,
+    Destination::Accumulator,
+    Destination::ProgramCounterLatch,
+    Destination::ProgramCounter,
+    Destination::MemAddressLo,
+    Destination::MemAddressHi,
+    Destination::Serial,
+    Destination::Led,
+    Destination::CarrySet,
+    Destination::CarryReset,
+    Destination::ExpansionSelect,
+    Destination::Spare(SpareDestination::D),
+    Destination::Spare(SpareDestination::E),
+    Destination::Spare(SpareDestination::F),
+];
+
+/// Sources by the top two bits of an opcode, with the operand read separately
+const FETCHES: [Fetch; 4] = [
+    Fetch::Expansion,
+    Fetch::Accumulator,
+    Fetch::Memory,
+    Fetch::Operand(0x00),
+];
+
 impl FromByte for Destination {
-    fn from_byte(byte: u8) -> Result<Self, DecoderError> {
-        match byte & 0b00_1111_00 {
-            0b00_0000_00 => Ok(Destination::Memory),
-            0b00_0001_00 => Ok(Destination::AccumulatorPlus),
-            0b00_0010_00 => Ok(Destination::AccumulatorNand),
-            0b00_0011_00 => Ok(Destination::Accumulator),
-            0b00_0100_00 => Ok(Destination::ProgramCounterLatch),
-            0b00_0101_00 => Ok(Destination::ProgramCounter),
-            0b00_0110_00 => Ok(Destination::MemAddressLo),
-            0b00_0111_00 => Ok(Destination::MemAddressHi),
-            0b00_1001_00 => Ok(Destination::Led),
-            0b00_1010_00 => Ok(Destination::CarrySet),
-            0b00_1011_00 => Ok(Destination::CarryReset),
-            _ => Err(DecoderError::UnrecognizedDestination),
-        }
+    fn from_byte(byte: u8) -> Self {
+        DESTINATIONS[usize::from(byte >> 2 & 0b1111)]
     }
 }
 
-impl FromByte for Source {
-    fn from_byte(byte: u8) -> Result<Self, DecoderError> {
-        Ok(match byte & 0b11_000000 {
-            0b00_000000 => Source::Expansion,
-            0b01_000000 => Source::Accumulator,
-            0b10_000000 => Source::Memory,
-            0b11_000000 => Source::Operand(0x00),
-            _ => unreachable!(),
-        })
+impl FromByte for Fetch {
+    fn from_byte(byte: u8) -> Self {
+        FETCHES[usize::from(byte >> 6)]
     }
 }
 
-impl FromByte for Operation {
-    fn from_byte(byte: u8) -> Result<Self, DecoderError> {
-        let cond_carry = byte & 0b000000_01 == 0b000000_01;
-        let cond_1 = byte & 0b000000_10 == 0b000000_10;
-        Ok(Operation {
-            src: Source::from_byte(byte)?,
-            dest: Destination::from_byte(byte)?,
-            cond_carry,
-            cond_1,
-        })
+/// Attempts to decode the instruction in `buf` at `program_counter`, along with its operand if
+/// it has one. Only running off the end of `buf` can fail.
+pub fn read_micro_op(buf: &[u8], program_counter: usize) -> Result<MicroOp, DecoderError> {
+    let opcode = *buf.get(program_counter).ok_or(DecoderError::InvalidRead)?;
+    let mut fetch = Fetch::from_byte(opcode);
+    let mut length = 1;
+    if let Fetch::Operand(value) = &mut fetch {
+        *value = *buf
+            .get(program_counter + 1)
+            .ok_or(DecoderError::InvalidRead)?;
+        length += 1;
     }
+    Ok(MicroOp {
+        fetch,
+        dest: Destination::from_byte(opcode),
+        cond_1: opcode & 0b10 != 0,
+        cond_carry: opcode & 0b01 != 0,
+        length,
+    })
 }
 
 /// Attempts to read the operation in `buf` at `program_counter`, returning the amount the program
@@ -63,20 +79,28 @@ pub fn read_operation(
     buf: &[u8],
     program_counter: usize,
 ) -> Result<(Operation, usize), DecoderError> {
-    let instruction = *buf.get(program_counter).ok_or(DecoderError::InvalidRead)?;
-    let mut advance = 1;
-    let mut op = Operation::from_byte(instruction)?;
-    if let Source::Operand(value) = &mut op.src {
-        *value = *buf.get(program_counter + 1).ok_or(DecoderError::InvalidRead)?;
-        advance += 1;
+    let op = read_micro_op(buf, program_counter)?;
+    Ok((op.operation(), op.length as usize))
+}
+
+/// Decode `program` from the start into one operation per line, in the syntax the assembler
+/// reads
+pub fn disassemble(program: &[u8]) -> Result<String, DecoderError> {
+    let mut text = String::new();
+    let mut program_counter = 0;
+    while program_counter < program.len() {
+        let (op, advance) = read_operation(program, program_counter)?;
+        text.push_str(&op.to_string());
+        text.push('\n');
+        program_counter += advance;
     }
-    Ok((op, advance))
+    Ok(text)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use assembler::assemble;
+    use assembler::{assemble, assemble_for, AssemblerError, Error, Options};
 
     #[test]
     fn test_roundtrip() {
@@ -89,7 +113,8 @@ hi@im_a_label -> PC
 55 -> RAM.low : if_carry | if_1
 FF -> RAM.high : if_1 | if_carry
 im_also_a_label:
-lo@im_also_a_label -> PC.latch";
+lo@im_also_a_label -> PC.latch
+ACC -> spare.D : if_carry";
         let bytecode = assemble(text).unwrap();
         let mut program_counter = 0;
         let mut ops = Vec::new();
@@ -147,7 +172,66 @@ lo@im_also_a_label -> PC.latch";
                 cond_1: false,
                 cond_carry: false,
             },
+            Operation {
+                src: Source::Accumulator,
+                dest: Destination::Spare(SpareDestination::D),
+                cond_1: false,
+                cond_carry: true,
+            },
         ];
         assert_eq!(ops, expected_ops)
     }
+
+    #[test]
+    fn test_operand_opcodes() {
+        // Every opcode with the top two bits set reads an operand, including the ones that used
+        // to end up in an unreachable arm
+        for opcode in 0b11_000000..=0xFF {
+            let (op, advance) = read_operation(&[opcode, 0x5A], 0).unwrap();
+            assert_eq!(op.src, Source::Operand(0x5A));
+            assert_eq!(advance, 2);
+            assert!(matches!(
+                read_operation(&[opcode], 0),
+                Err(DecoderError::InvalidRead)
+            ));
+        }
+        let (op, _) = read_operation(&[0xFF, 0x00], 0).unwrap();
+        assert_eq!(op.dest, Destination::Spare(SpareDestination::F));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let text = "5F -> LED\nACC -> PC.latch : if_carry | if_1\nEXP -> spare.E\n";
+        let options = Options {
+            reset: Reset {
+                skip_first_fetch: false,
+                ..Reset::default()
+            },
+            ..Options::default()
+        };
+        let bytes = assemble_for(text, &options).unwrap().bytes;
+        assert_eq!(disassemble(&bytes).unwrap(), text);
+        assert!(disassemble(&bytes[..1]).is_err());
+
+        // Every encoding disassembles to something that assembles back to it, apart from moving
+        // an operand to RAM, which the assembler refuses
+        for opcode in 0..=255u8 {
+            let operands = if opcode >> 6 == 0b11 { 0..=255 } else { 0..=0 };
+            for operand in operands {
+                let bytes = if opcode >> 6 == 0b11 {
+                    vec![opcode, operand]
+                } else {
+                    vec![opcode]
+                };
+                let text = disassemble(&bytes).unwrap();
+                match assemble_for(&text, &options) {
+                    Ok(assembly) => assert_eq!(assembly.bytes, bytes, "{}", text),
+                    Err(Error::Assembler(AssemblerError::ForbiddenInstruction { .. })) => {
+                        assert_eq!(opcode & 0b11_1111_00, 0b11_0000_00)
+                    }
+                    Err(e) => panic!("{}: {}", text, e),
+                }
+            }
+        }
+    }
 }
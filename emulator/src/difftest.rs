@@ -0,0 +1,391 @@
+//! Comparing the emulator against register traces written by a simulation of the hardware
+//!
+//! A trace has one line per clock cycle or one line per instruction, each made of `name=value`
+//! pairs such as `cycle=12 pc=0005 acc=3F flag_carry=1`. `cycle` is decimal and counted from the
+//! first line, everything else is hex. Registers use the names from the signal VCD and any that
+//! are left out aren't compared. Without `cycle`, the first line is the state after reset and
+//! each line after it is the state after one more instruction. `#` starts a comment.
+
+use crate::waveform::Signals;
+use crate::{Emulator, EmulatorError};
+use anyhow::{bail, Context, Result};
+use common::Operation;
+use std::fmt;
+
+/// Registers a trace can contain
+pub const REGISTERS: [&str; 9] = [
+    "pc",
+    "pc_latch",
+    "acc",
+    "ram_low",
+    "ram_high",
+    "flag_1",
+    "flag_carry",
+    "led",
+    "exp_sel",
+];
+
+fn register(signals: &Signals, name: &str) -> u64 {
+    match name {
+        "pc" => signals.pc as u64,
+        "pc_latch" => signals.pc_latch as u64,
+        "acc" => signals.acc as u64,
+        "ram_low" => signals.ram_low as u64,
+        "ram_high" => signals.ram_high as u64,
+        "flag_1" => signals.flag_1 as u64,
+        "flag_carry" => signals.flag_carry as u64,
+        "led" => signals.led as u64,
+        "exp_sel" => signals.expansion_select as u64,
+        _ => unreachable!("Not a register: {}", name),
+    }
+}
+
+/// Width of a register in bits
+fn width(name: &str) -> u32 {
+    match name {
+        "pc" => 16,
+        "flag_1" | "flag_carry" => 1,
+        _ => 8,
+    }
+}
+
+/// Hex digits to show a register with
+fn digits(name: &str) -> usize {
+    (width(name) as usize).div_ceil(4)
+}
+
+/// The registers on one line of a trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceLine {
+    /// Line number in the trace file
+    pub line: usize,
+    /// Clock cycle, counted from the first line, which is at 0
+    pub cycle: Option<u64>,
+    pub registers: Vec<(&'static str, u64)>,
+}
+
+/// Parse a trace, checking that either every line has a cycle count or none do
+pub fn parse_trace(text: &str) -> Result<Vec<TraceLine>> {
+    let mut trace: Vec<TraceLine> = Vec::new();
+    // The cycle on the first line, which the others are counted from
+    let mut first = None;
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut cycle = None;
+        let mut registers = Vec::new();
+        for pair in line.split_whitespace() {
+            let (name, value) = match pair.find('=') {
+                Some(index) => (&pair[..index], &pair[index + 1..]),
+                None => bail!("Expected name=value on line {}", line_number + 1),
+            };
+            if name == "cycle" {
+                cycle = Some(
+                    value
+                        .parse::<u64>()
+                        .with_context(|| format!("Bad cycle on line {}", line_number + 1))?,
+                );
+                continue;
+            }
+            let name = match REGISTERS.iter().find(|r| **r == name) {
+                Some(name) => *name,
+                None => bail!("Unknown register {} on line {}", name, line_number + 1),
+            };
+            let value = u64::from_str_radix(value, 16)
+                .ok()
+                .filter(|v| *v < 1 << width(name))
+                .with_context(|| format!("Bad value for {} on line {}", name, line_number + 1))?;
+            registers.push((name, value));
+        }
+        if let Some(previous) = trace.last() {
+            if previous.cycle.is_some() != cycle.is_some() {
+                bail!(
+                    "Line {} should have a cycle if and only if the others do",
+                    line_number + 1
+                );
+            }
+        }
+        if let Some(value) = cycle {
+            let offset = value.checked_sub(*first.get_or_insert(value));
+            if offset.is_none() || trace.last().is_some_and(|previous| previous.cycle > offset) {
+                bail!("Cycle goes backwards on line {}", line_number + 1);
+            }
+            cycle = offset;
+        }
+        trace.push(TraceLine {
+            line: line_number + 1,
+            cycle,
+            registers,
+        });
+    }
+    Ok(trace)
+}
+
+/// The first place the emulator and a trace disagree
+#[derive(Debug)]
+pub struct Divergence {
+    /// Line of the trace that doesn't match
+    pub line: usize,
+    /// Clock cycles the emulator had run for
+    pub cycle: u64,
+    /// The last instruction executed, and its address
+    pub previous: Option<(u16, Operation)>,
+    /// The next instruction to execute, and its address
+    pub next: Option<(u16, Operation)>,
+    /// Registers that differ, with their value in the trace and in the emulator
+    pub registers: Vec<(&'static str, u64, u64)>,
+    /// Why the emulator stopped before reaching the line, if it did
+    pub error: Option<EmulatorError>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Diverged at line {}, cycle {}", self.line, self.cycle)?;
+        if let Some((address, op)) = &self.previous {
+            writeln!(f, "  {:04X}  {}", address, op)?;
+        }
+        if let Some((address, op)) = &self.next {
+            writeln!(f, "> {:04X}  {}", address, op)?;
+        }
+        if let Some(error) = &self.error {
+            writeln!(f, "Emulator stopped: {}", error)?;
+        }
+        if !self.registers.is_empty() {
+            writeln!(f, "{:<12}{:<8}emulator", "register", "trace")?;
+        }
+        for (name, expected, actual) in &self.registers {
+            writeln!(
+                f,
+                "{:<12}{:<8}{:0w$X}",
+                name,
+                format!("{:0w$X}", expected, w = digits(name)),
+                actual,
+                w = digits(name)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// How much of a trace the emulator matched
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub lines: usize,
+    /// Lines that fell in the middle of an instruction, before its destination was written
+    pub mid_instruction: usize,
+    /// Clock cycles the emulator ran for
+    pub cycles: u64,
+}
+
+/// An instruction the emulator executed
+struct Executed {
+    address: u16,
+    op: Operation,
+    /// The cycle it started on
+    start: u64,
+    /// Its signals, see `Signals::on_cycle`
+    during: Signals,
+}
+
+/// Step the emulator, returning the instruction it executed
+fn step(emulator: &mut Emulator) -> Result<Executed, EmulatorError> {
+    let address = emulator.pc.get();
+    let (op, _) = emulator.decode(address)?;
+    let during = Signals {
+        opcode: emulator.program.get(address as usize).copied(),
+        ..emulator.signals()
+    };
+    let start = emulator.cycles();
+    emulator.step()?;
+    Ok(Executed {
+        address,
+        op,
+        start,
+        during,
+    })
+}
+
+/// Run the emulator alongside `trace`, stopping at the first line it doesn't match. A line that
+/// falls in the middle of an instruction is compared against the registers as they are on that
+/// cycle, which haven't been written yet.
+pub fn compare(emulator: &mut Emulator, trace: &[TraceLine]) -> Result<Summary, Box<Divergence>> {
+    let base = emulator.cycles();
+    let mut previous: Option<Executed> = None;
+    let mut mid_instruction = 0;
+    for (index, line) in trace.iter().enumerate() {
+        let mut stopped = None;
+        let mut cycle = None;
+        let mut signals = None;
+        match line.cycle {
+            // Count in cycles since the comparison started, which can't overflow whatever the
+            // trace says
+            Some(target) => {
+                while stopped.is_none() && emulator.cycles() - base < target {
+                    match step(emulator) {
+                        Ok(executed) => previous = Some(executed),
+                        Err(e) => stopped = Some(e),
+                    }
+                }
+                if stopped.is_none() {
+                    cycle = Some(target);
+                    let past = |_: &&Executed| emulator.cycles() - base > target;
+                    if let Some(executed) = previous.as_ref().filter(past) {
+                        let length = emulator.cycles() - executed.start;
+                        let into = target - (executed.start - base);
+                        signals = Some(executed.during.on_cycle(into, length));
+                        mid_instruction += 1;
+                    }
+                }
+            }
+            None if index > 0 => match step(emulator) {
+                Ok(executed) => previous = Some(executed),
+                Err(e) => stopped = Some(e),
+            },
+            None => (),
+        }
+
+        let registers = match stopped {
+            Some(_) => Vec::new(),
+            None => {
+                let signals = signals.unwrap_or_else(|| emulator.signals());
+                line.registers
+                    .iter()
+                    .map(|&(name, expected)| (name, expected, register(&signals, name)))
+                    .filter(|(_, expected, actual)| expected != actual)
+                    .collect::<Vec<_>>()
+            }
+        };
+        if stopped.is_some() || !registers.is_empty() {
+            // A step that fails leaves the program counter on the instruction at fault
+            let pc = emulator.pc.get();
+            return Err(Box::new(Divergence {
+                line: line.line,
+                cycle: cycle.unwrap_or(emulator.cycles() - base),
+                previous: previous.map(|executed| (executed.address, executed.op)),
+                next: emulator.decode(pc).ok().map(|(op, _)| (pc, op)),
+                registers,
+                error: stopped,
+            }));
+        }
+    }
+    Ok(Summary {
+        lines: trace.len(),
+        mid_instruction,
+        cycles: emulator.cycles() - base,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emulator() -> Emulator {
+        let program = assembler::assemble(
+            "
+05 -> ACC
+ACC -> ACC.plus
+ACC -> LED",
+        )
+        .unwrap();
+        Emulator::from_program(program.into_boxed_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_trace() {
+        let trace =
+            parse_trace("# header\ncycle=4 pc=0001 flag_1=1\n\ncycle=5 acc=3f # ok\n").unwrap();
+        assert_eq!(
+            trace,
+            vec![
+                TraceLine {
+                    line: 2,
+                    cycle: Some(0),
+                    registers: vec![("pc", 0x0001), ("flag_1", 1)],
+                },
+                TraceLine {
+                    line: 4,
+                    cycle: Some(1),
+                    registers: vec![("acc", 0x3F)],
+                },
+            ]
+        );
+        assert!(parse_trace("pc=0001\ncycle=1 pc=0002").is_err());
+        assert!(parse_trace("cycle=2\ncycle=1").is_err());
+        let trace = parse_trace(&format!("cycle=5\ncycle={}", u64::MAX)).unwrap();
+        assert_eq!(trace[1].cycle, Some(u64::MAX - 5));
+        assert!(parse_trace("acc=100").is_err());
+        assert!(parse_trace("flag_1=2").is_err());
+        assert!(parse_trace("sp=00").is_err());
+        assert!(parse_trace("acc").is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        // Per instruction
+        let trace = parse_trace("pc=0000\npc=0001 acc=00\npc=0003 acc=05\nacc=0A\nled=0A").unwrap();
+        assert_eq!(
+            compare(&mut emulator(), &trace).unwrap(),
+            Summary {
+                lines: 5,
+                mid_instruction: 0,
+                cycles: 5,
+            }
+        );
+
+        // Per cycle, starting from a cycle other than 0 and with a line in the middle of an
+        // instruction, where the operand is being fetched
+        let trace = parse_trace(
+            "cycle=10 pc=0000\ncycle=11 pc=0001\ncycle=12 pc=0002 acc=00\ncycle=13 pc=0003 acc=05",
+        )
+        .unwrap();
+        assert_eq!(
+            compare(&mut emulator(), &trace).unwrap(),
+            Summary {
+                lines: 4,
+                mid_instruction: 1,
+                cycles: 3,
+            }
+        );
+        let trace = parse_trace("cycle=0 pc=0000\ncycle=2 acc=05").unwrap();
+        let divergence = compare(&mut emulator(), &trace).unwrap_err();
+        assert_eq!(divergence.cycle, 2);
+        assert_eq!(divergence.registers, vec![("acc", 0x05, 0x00)]);
+
+        let trace = parse_trace(
+            "cycle=10 pc=0000\ncycle=11 pc=0001\ncycle=12 pc=0002\ncycle=13 pc=0003 acc=05\n\
+             cycle=14 acc=0B",
+        )
+        .unwrap();
+        let divergence = compare(&mut emulator(), &trace).unwrap_err();
+        assert_eq!(divergence.line, 5);
+        assert_eq!(divergence.cycle, 4);
+        assert_eq!(divergence.registers, vec![("acc", 0x0B, 0x0A)]);
+        assert_eq!(
+            divergence.to_string(),
+            "Diverged at line 5, cycle 4
+  0003  ACC -> ACC.plus
+> 0004  ACC -> LED
+register    trace   emulator
+acc         0B      0A
+"
+        );
+
+        // Running off the end of the ROM
+        let trace = parse_trace("pc=0000\nacc=00\nacc=05\nacc=0A\nled=0A\nled=0A").unwrap();
+        let divergence = compare(&mut emulator(), &trace).unwrap_err();
+        assert_eq!(divergence.line, 6);
+        assert!(divergence.error.is_some());
+        assert!(divergence.next.is_none());
+
+        // A huge cycle count after the emulator has already run runs off the end of the ROM
+        // rather than overflowing
+        let mut emu = emulator();
+        emu.step().unwrap();
+        let trace = parse_trace(&format!("cycle=0 pc=0001\ncycle={}", u64::MAX)).unwrap();
+        let divergence = compare(&mut emu, &trace).unwrap_err();
+        assert_eq!(divergence.line, 2);
+        assert!(divergence.error.is_some());
+    }
+}
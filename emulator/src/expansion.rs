@@ -0,0 +1,128 @@
+use crate::Word;
+use common::{ParseError, SpareDestination};
+use std::fmt::Debug;
+use std::str::FromStr;
+
+/// Hardware attached to the expansion bus
+pub trait ExpansionBus: Debug {
+    /// Called when a spare destination (1101-1111) is written while
+    /// `SpareDestinations::Expansion` is selected
+    fn spare(&mut self, spare: SpareDestination, value: Word);
+
+    /// Called when `EXP` is read. `select` is the value last written to `EXP.sel` and `cycles`
+    /// the clock cycles since reset. Nothing drives the bus by default, so it reads as 0.
+    fn read(&mut self, _select: Word, _cycles: u64) -> Word {
+        0
+    }
+}
+
+/// What the unassigned destination encodings 1101-1111 do on a given hardware revision
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SpareDestinations {
+    /// Stop with `EmulatorError::UnassignedDestination`
+    #[default]
+    Error,
+    /// Do nothing
+    Nop,
+    /// Hand the write to the expansion bus
+    Expansion,
+}
+
+impl FromStr for SpareDestinations {
+    type Err = ParseError;
+
+    /// `error`, `nop` or `expansion`, as given to `--spare-destinations`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(SpareDestinations::Error),
+            "nop" => Ok(SpareDestinations::Nop),
+            "expansion" => Ok(SpareDestinations::Expansion),
+            _ => Err(ParseError {
+                message: format!(
+                    "Spare destinations can be error, nop or expansion, not \"{}\"",
+                    s
+                ),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Emulator, EmulatorError};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        writes: Rc<RefCell<Vec<(SpareDestination, Word)>>>,
+    }
+
+    impl ExpansionBus for Recorder {
+        fn spare(&mut self, spare: SpareDestination, value: Word) {
+            self.writes.borrow_mut().push((spare, value));
+        }
+    }
+
+    fn emulator(spare: SpareDestinations) -> Emulator {
+        let program = assembler::assemble("5F -> spare.E\nA0 -> spare.D : if_1").unwrap();
+        let mut emu = Emulator::from_program(program.into_boxed_slice()).unwrap();
+        emu.spare_destinations = spare;
+        // Past the dummy NOP
+        emu.step().unwrap();
+        emu
+    }
+
+    #[test]
+    fn test_spare_error() {
+        let mut emu = emulator(SpareDestinations::Error);
+        assert!(matches!(
+            emu.step(),
+            Err(EmulatorError::UnassignedDestination {
+                pc: 0x0001,
+                opcode: 0b11_1110_00,
+                ..
+            })
+        ));
+
+        // Skipped, since flag 1 is clear, so nothing is written
+        emu.pc.set(0x0003);
+        emu.step().unwrap();
+        assert_eq!(emu.pc.get(), 5);
+    }
+
+    #[test]
+    fn test_spare_nop() {
+        let mut emu = emulator(SpareDestinations::Nop);
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.pc.get(), 5);
+    }
+
+    #[test]
+    fn test_spare_expansion() {
+        let mut emu = emulator(SpareDestinations::Expansion);
+        assert!(matches!(
+            emu.step(),
+            Err(EmulatorError::NoExpansionBus { .. })
+        ));
+        emu.pc.set(0x0003);
+        emu.step().unwrap();
+
+        let mut emu = emulator(SpareDestinations::Expansion);
+        let recorder = Recorder::default();
+        let writes = recorder.writes.clone();
+        emu.expansion = Some(Box::new(recorder));
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert_eq!(*writes.borrow(), vec![(SpareDestination::E, 0x5F)]);
+    }
+
+    #[test]
+    fn test_parse_spare_destinations() {
+        assert_eq!("nop".parse(), Ok(SpareDestinations::Nop));
+        assert_eq!("expansion".parse(), Ok(SpareDestinations::Expansion));
+        assert!("NOP".parse::<SpareDestinations>().is_err());
+    }
+}
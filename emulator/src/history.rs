@@ -0,0 +1,216 @@
+use crate::Word;
+use std::collections::VecDeque;
+
+/// The previous value of a piece of machine state, recorded just before it is overwritten
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    ProgramCounterLatch(Word),
+    Accumulator(Word),
+    Flag1(bool),
+    FlagCarry(bool),
+    MemAddressLo(Word),
+    MemAddressHi(Word),
+    Memory {
+        address: u16,
+        value: Word,
+    },
+    Led(Word),
+    ExpansionSelect(Word),
+    /// A byte was written to `Serial.out`. Undoing it takes the byte back if nobody has read it
+    /// yet.
+    Serial,
+}
+
+/// Everything needed to undo a single step. An instruction touches at most one register and one
+/// flag, so two slots are always enough.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub pc: u16,
+    pub cycles: u64,
+    /// Whether the instruction was conditional and whether it executed, to rewind the profile
+    pub conditional: bool,
+    pub executed: bool,
+    pub changes: [Option<Change>; 2],
+}
+
+/// Bounded undo log of executed steps, oldest first
+#[derive(Debug, Default)]
+pub struct History {
+    capacity: usize,
+    steps: VecDeque<Step>,
+}
+
+impl History {
+    /// A history keeping at most `capacity` steps. A capacity of zero disables recording.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            steps: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Record a step, forgetting the oldest one if the history is full
+    pub fn push(&mut self, step: Step) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(step);
+    }
+
+    /// Add a change to the most recently recorded step
+    pub fn record(&mut self, change: Change) {
+        if let Some(step) = self.steps.back_mut() {
+            if let Some(slot) = step.changes.iter_mut().find(|c| c.is_none()) {
+                *slot = Some(change);
+            }
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Step> {
+        self.steps.pop_back()
+    }
+
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Emulator;
+    use std::collections::HashSet;
+
+    fn emulator(text: &str) -> Emulator {
+        let program = assembler::assemble(text).unwrap();
+        let mut emu = Emulator::from_program(program.into_boxed_slice()).unwrap();
+        emu.enable_history(64);
+        emu
+    }
+
+    fn state(emu: &Emulator) -> (u16, u64, u8, u8, bool, bool, u16, u8, u8) {
+        let mut mem = emu.mem.values.clone();
+        mem.resize(2, 0);
+        (
+            emu.pc.get(),
+            emu.cycles(),
+            emu.pc.latch,
+            emu.acc.get(),
+            emu.flag_1,
+            emu.flag_carry,
+            emu.mem.address(),
+            mem[1],
+            emu.led.get(),
+        )
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut emu = emulator(
+            "
+01 -> RAM.low
+3F -> ACC
+ACC -> RAM
+FF -> ACC
+ACC -> carry.set
+C0 -> ACC.plus
+ACC -> LED
+02 -> PC.latch",
+        );
+        let mut states = vec![state(&emu)];
+        for _ in 0..9 {
+            emu.step().unwrap();
+            states.push(state(&emu));
+        }
+        states.pop();
+        while let Some(expected) = states.pop() {
+            assert!(emu.step_back());
+            assert_eq!(state(&emu), expected);
+        }
+        assert!(!emu.step_back());
+    }
+
+    #[test]
+    fn test_step_back_serial_and_profile() {
+        let mut emu =
+            emulator("'a' -> Serial.out\n'b' -> Serial.out : if_carry\n'c' -> Serial.out");
+        emu.enable_profiling();
+        for _ in 0..4 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.serial_out, b"ac");
+        let profile = emu.profile.clone().unwrap();
+        assert_eq!(profile.skipped[3], 1);
+
+        assert!(emu.step_back());
+        assert_eq!(emu.serial_out, b"a");
+        assert!(emu.step_back());
+        assert!(emu.step_back());
+        assert!(emu.serial_out.is_empty());
+        let profile = emu.profile.as_ref().unwrap();
+        assert_eq!(profile.fetched[..5], [1, 0, 0, 0, 0]);
+        assert_eq!(profile.skipped[3], 0);
+
+        // Bytes that were already taken stay sent
+        emu.step().unwrap();
+        assert_eq!(emu.take_serial(), b"a");
+        assert!(emu.step_back());
+        assert!(emu.serial_out.is_empty());
+    }
+
+    #[test]
+    fn test_bounded_history() {
+        let mut emu = emulator("loop:\n01 -> ACC.plus\nlo@loop -> PC");
+        emu.enable_history(4);
+        for _ in 0..20 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.history.len(), 4);
+        for _ in 0..4 {
+            assert!(emu.step_back());
+        }
+        assert!(!emu.step_back());
+    }
+
+    #[test]
+    fn test_reverse_continue() {
+        let mut emu = emulator(
+            "
+00 -> ACC
+loop:
+01 -> ACC.plus
+lo@loop -> PC",
+        );
+        for _ in 0..10 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.acc.get(), 4);
+        let breakpoints: HashSet<u16> = [3].iter().copied().collect();
+        assert!(emu.reverse_continue(&breakpoints));
+        assert_eq!(emu.pc.get(), 3);
+        assert_eq!(emu.acc.get(), 3);
+        assert!(emu.reverse_continue(&breakpoints));
+        assert_eq!(emu.acc.get(), 2);
+        assert!(!emu.reverse_continue(&HashSet::new()));
+        assert_eq!(emu.pc.get(), 0);
+        assert_eq!(emu.acc.get(), 0);
+    }
+}
@@ -1,6 +1,25 @@
 use thiserror::Error;
-mod decoder;
+pub mod decoder;
+pub mod difftest;
+pub mod expansion;
+pub mod history;
+pub mod predecode;
+pub mod profile;
+pub mod serial;
+pub mod timing;
+pub mod waveform;
 use common::*;
+use expansion::{ExpansionBus, SpareDestinations};
+use history::{Change, History, Step};
+use predecode::{Fetch, MicroOp, Predecoded};
+use profile::Profile;
+use std::collections::{HashSet, VecDeque};
+use timing::Timing;
+use waveform::{LedTrace, SignalTrace, Signals};
+
+/// The most bytes written to `Serial.out` that are kept waiting for `take_serial`. Older ones are
+/// dropped, so a program that writes forever can't use up memory.
+pub const SERIAL_LIMIT: usize = 0x1000;
 
 #[derive(Default, Debug)]
 pub struct Emulator {
@@ -11,51 +30,344 @@ pub struct Emulator {
     pub acc: Accumulator,
     pub mem: Memory,
     pub led: Led,
+    pub history: History,
+    pub profile: Option<Profile>,
+    pub timing: Timing,
+    /// Clock cycles elapsed since reset
+    pub cycles: u64,
+    pub predecoded: Option<Predecoded>,
+    pub reset: Reset,
+    pub spare_destinations: SpareDestinations,
+    pub expansion: Option<Box<dyn ExpansionBus>>,
+    /// The last value written to `EXP.sel`, which picks what `EXP` reads
+    pub expansion_select: Word,
+    /// Bytes written to `Serial.out` that haven't been taken yet
+    serial_out: VecDeque<Word>,
+    pub led_trace: Option<LedTrace>,
+    pub signal_trace: Option<SignalTrace>,
 }
 
 impl Emulator {
+    /// Load a program for the current board
     pub fn from_program(program: Box<[u8]>) -> Result<Self, EmulatorError> {
-        let first_instruction = *program
-            .get(0)
-            .ok_or(EmulatorError::Decoder(decoder::DecoderError::InvalidRead))?;
-        if first_instruction != 0b01001100 {
-            return Err(EmulatorError::MissingNop);
-        }
-        Ok(Self {
+        Self::with_reset(program, Reset::default())
+    }
+
+    /// Load a program for a board with the given reset behavior
+    pub fn with_reset(program: Box<[u8]>, reset: Reset) -> Result<Self, EmulatorError> {
+        let mut emulator = Self {
             program,
+            reset,
+            ..Default::default()
+        };
+        emulator.reset()?;
+        Ok(emulator)
+    }
+
+    /// Put the registers and flags back into their reset state. Memory is left as it is.
+    pub fn reset(&mut self) -> Result<(), EmulatorError> {
+        if self.reset.skip_first_fetch {
+            let (first, _) = self.decode(self.reset.vector)?;
+            if first != NOP {
+                return Err(EmulatorError::MissingNop);
+            }
+        }
+        // The dummy NOP is executed like any other instruction
+        self.pc.set(self.reset.vector);
+        self.pc.latch(self.reset.pc_latch);
+        self.acc.value = self.reset.acc;
+        self.mem.latch_low(self.reset.mem_address as u8);
+        self.mem.latch_high((self.reset.mem_address >> 8) as u8);
+        self.led.set(self.reset.led);
+        self.flag_1 = self.reset.flag_1;
+        self.flag_carry = self.reset.flag_carry;
+        self.expansion_select = 0;
+        self.cycles = 0;
+        self.history.clear();
+        if self.led_trace.is_some() {
+            self.enable_led_trace();
+        }
+        if self.signal_trace.is_some() {
+            self.enable_signal_trace();
+        }
+        Ok(())
+    }
+
+    /// Start recording an undo log of at most `capacity` steps, discarding any existing history
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = History::with_capacity(capacity);
+    }
+
+    /// Start recording every change to the LEDs, discarding any existing trace
+    pub fn enable_led_trace(&mut self) {
+        self.led_trace = Some(LedTrace::new(self.cycles, self.led.get()));
+    }
+
+    /// Start recording the registers and control lines of every instruction, discarding any
+    /// existing trace
+    pub fn enable_signal_trace(&mut self) {
+        self.signal_trace = Some(SignalTrace::new(self.cycles, self.signals()));
+    }
+
+    /// The registers as they are, with no instruction executing
+    pub fn signals(&self) -> Signals {
+        Signals {
+            pc: self.pc.get(),
+            pc_latch: self.pc.latch,
+            acc: self.acc.get(),
+            ram_low: self.mem.low_latch,
+            ram_high: self.mem.hi_latch,
+            flag_1: self.flag_1,
+            flag_carry: self.flag_carry,
+            led: self.led.get(),
+            expansion_select: self.expansion_select,
             ..Default::default()
+        }
+    }
+
+    /// The bytes written to `Serial.out` since the last call, at most the last `SERIAL_LIMIT`
+    pub fn take_serial(&mut self) -> Vec<Word> {
+        self.serial_out.drain(..).collect()
+    }
+
+    /// Start counting executions of each ROM address, discarding any existing profile
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::new(self.program.len()));
+    }
+
+    /// Decode the whole ROM once so that `step` doesn't have to. Call this again (or use
+    /// `write_rom`) after modifying `program`.
+    pub fn predecode(&mut self) {
+        self.predecoded = Some(Predecoded::new(&self.program));
+    }
+
+    /// Overwrite a byte of ROM, keeping the predecoded instructions up to date
+    pub fn write_rom(&mut self, address: u16, value: Word) {
+        let address = address as usize;
+        if let Some(byte) = self.program.get_mut(address) {
+            *byte = value;
+            if let Some(predecoded) = &mut self.predecoded {
+                predecoded.invalidate(&self.program, address);
+            }
+        }
+    }
+
+    /// Decode the instruction at `address`, returning it and its length
+    pub fn decode(&self, address: u16) -> Result<(Operation, usize), EmulatorError> {
+        let op = self.decode_micro_op(address)?;
+        Ok((op.operation(), op.length as usize))
+    }
+
+    fn decode_micro_op(&self, address: u16) -> Result<MicroOp, EmulatorError> {
+        decoder::read_micro_op(&self.program, address as usize).map_err(|error| {
+            EmulatorError::Decoder {
+                pc: address,
+                opcode: self.program.get(address as usize).copied(),
+                error,
+            }
         })
     }
 
+    /// Execute one instruction. If it can't be executed, nothing changes and the error says
+    /// which instruction it was.
     pub fn step(&mut self) -> Result<(), EmulatorError> {
-        let (op, advance) = decoder::read_operation(&self.program, self.pc.get() as usize)?;
-        self.pc.advance(advance as u16);
+        let pc = self.pc.get();
+        let op = match self.predecoded.as_ref().and_then(|p| p.get(pc)) {
+            Some(op) => op,
+            None => self.decode_micro_op(pc)?,
+        };
+        self.check(pc, &op)?;
+
+        let conditional = op.cond_1 || op.cond_carry;
+        let execute = self.condition_passes(op.cond_1, op.cond_carry);
+        let end = self.cycles + self.timing.cycles(op.length as usize);
+        // Read the source before changing anything, since there may be nothing on the expansion
+        // bus to read
+        let bus = match execute {
+            true => match self.pull(op.fetch, end) {
+                Some(word) => Some(word),
+                None => {
+                    let opcode = self.program.get(pc as usize).copied().unwrap_or_default();
+                    let op = op.operation();
+                    return Err(EmulatorError::NoExpansionBus { pc, opcode, op });
+                }
+            },
+            false => None,
+        };
+        if self.history.is_enabled() {
+            self.history.push(Step {
+                pc,
+                cycles: self.cycles,
+                conditional,
+                executed: execute,
+                changes: [None, None],
+            });
+        }
+        let start = self.cycles;
+        let before = self.signal_trace.as_ref().map(|_| self.signals());
+        self.pc.advance(op.length as u16);
+        self.cycles = end;
+
+        if let Some(profile) = &mut self.profile {
+            profile.record(pc, conditional, execute);
+        }
+
+        if let Some(word) = bus {
+            if self.history.is_enabled() {
+                self.record_undo(&op.dest);
+            }
+            self.push(op.dest, word);
+        }
+
+        if let Some(before) = before {
+            let during = Signals {
+                opcode: self.program.get(pc as usize).copied(),
+                write: execute,
+                bus,
+                ..before
+            };
+            let after = self.signals();
+            if let Some(trace) = &mut self.signal_trace {
+                trace.record(start, during, self.cycles, after);
+            }
+        }
+
+        Ok(())
+    }
 
-        if let (Source::Operand(_), Destination::Memory) = (&op.src, &op.dest) {
-            return Err(EmulatorError::Illegal(op));
+    /// Make sure `op`, fetched from `pc`, can run on this board. Spare destinations only matter
+    /// if the condition passes, but an illegal encoding never can. Reading `EXP` with nothing on
+    /// the expansion bus is caught by `pull`.
+    fn check(&self, pc: u16, op: &MicroOp) -> Result<(), EmulatorError> {
+        let execute = self.condition_passes(op.cond_1, op.cond_carry);
+        let spare = execute && matches!(op.dest, Destination::Spare(_));
+        let illegal = matches!(op.fetch, Fetch::Operand(_)) && op.dest == Destination::Memory;
+        let unassigned = spare && self.spare_destinations == SpareDestinations::Error;
+        let no_bus = self.expansion.is_none()
+            && spare
+            && self.spare_destinations == SpareDestinations::Expansion;
+        if !(illegal || unassigned || no_bus) {
+            return Ok(());
         }
+        let opcode = self.program.get(pc as usize).copied().unwrap_or_default();
+        let op = op.operation();
+        Err(if illegal {
+            EmulatorError::Illegal { pc, opcode, op }
+        } else if unassigned {
+            EmulatorError::UnassignedDestination { pc, opcode, op }
+        } else {
+            EmulatorError::NoExpansionBus { pc, opcode, op }
+        })
+    }
 
-        let execute = match (op.cond_1, op.cond_carry) {
+    /// Whether an instruction with these conditions would execute with the flags as they are
+    pub fn condition_passes(&self, cond_1: bool, cond_carry: bool) -> bool {
+        match (cond_1, cond_carry) {
             (false, false) => true,
             (true, false) => self.flag_1,
             (false, true) => self.flag_carry,
             (true, true) => self.flag_carry || self.flag_1,
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Undo the most recent step. Returns false if there is no history left. Serial output that
+    /// has already been read and values read from `EXP` can't be taken back.
+    pub fn step_back(&mut self) -> bool {
+        let step = match self.history.pop() {
+            Some(step) => step,
+            None => return false,
         };
+        for change in step.changes.iter().rev().flatten() {
+            match *change {
+                Change::ProgramCounterLatch(v) => self.pc.latch(v),
+                Change::Accumulator(v) => self.acc.value = v,
+                Change::Flag1(v) => self.flag_1 = v,
+                Change::FlagCarry(v) => self.flag_carry = v,
+                Change::MemAddressLo(v) => self.mem.latch_low(v),
+                Change::MemAddressHi(v) => self.mem.latch_high(v),
+                Change::Memory { address, value } => self.mem.poke(address, value),
+                Change::Led(v) => self.led.set(v),
+                Change::ExpansionSelect(v) => self.expansion_select = v,
+                Change::Serial => {
+                    self.serial_out.pop_back();
+                }
+            }
+        }
+        self.pc.set(step.pc);
+        self.cycles = step.cycles;
+        if let Some(profile) = &mut self.profile {
+            profile.unrecord(step.pc, step.conditional, step.executed);
+        }
+        if let Some(trace) = &mut self.led_trace {
+            trace.rewind(step.cycles);
+        }
+        if let Some(trace) = &mut self.signal_trace {
+            trace.rewind(step.cycles);
+        }
+        true
+    }
 
-        if execute {
-            let word = self.pull(op.src);
-            self.push(op.dest, word);
+    /// Step backwards until the program counter lands on one of `breakpoints`. Returns false if
+    /// the history ran out first.
+    pub fn reverse_continue(&mut self, breakpoints: &HashSet<u16>) -> bool {
+        while self.step_back() {
+            if breakpoints.contains(&self.pc.get()) {
+                return true;
+            }
         }
+        false
+    }
 
-        Ok(())
+    /// Save the state that writing to `dest` is about to overwrite
+    fn record_undo(&mut self, dest: &Destination) {
+        let changes: &[Change] = match dest {
+            Destination::ProgramCounterLatch => &[Change::ProgramCounterLatch(self.pc.latch)],
+            Destination::Accumulator => &[
+                Change::Accumulator(self.acc.get()),
+                Change::Flag1(self.flag_1),
+            ],
+            Destination::AccumulatorPlus => &[
+                Change::Accumulator(self.acc.get()),
+                Change::FlagCarry(self.flag_carry),
+            ],
+            Destination::AccumulatorNand => &[Change::Accumulator(self.acc.get())],
+            Destination::Led => &[Change::Led(self.led.get())],
+            Destination::Memory => &[Change::Memory {
+                address: self.mem.address(),
+                value: self.mem.peek(self.mem.address()),
+            }],
+            Destination::MemAddressLo => &[Change::MemAddressLo(self.mem.low_latch)],
+            Destination::MemAddressHi => &[Change::MemAddressHi(self.mem.hi_latch)],
+            Destination::CarrySet | Destination::CarryReset => {
+                &[Change::FlagCarry(self.flag_carry)]
+            }
+            Destination::ExpansionSelect => &[Change::ExpansionSelect(self.expansion_select)],
+            Destination::Serial => &[Change::Serial],
+            // Jumps are undone by restoring the program counter, and what a spare destination
+            // does is up to the expansion bus
+            Destination::ProgramCounter | Destination::Spare(_) => &[],
+        };
+        for change in changes {
+            self.history.record(*change);
+        }
     }
 
-    pub fn pull(&mut self, src: Source) -> Word {
+    /// The value `src` puts on the data bus at clock cycle `cycles`, or `None` if it's `EXP`
+    /// and nothing is on the expansion bus. Reading `EXP` can't be undone, since the bus may have
+    /// consumed what it returned.
+    fn pull(&mut self, src: Fetch, cycles: u64) -> Option<Word> {
         match src {
-            Source::Operand(value) => value,
-            Source::Accumulator => self.acc.get(),
-            Source::Memory => self.mem.read(),
-            _ => todo!("{:?}", src),
+            Fetch::Operand(value) => Some(value),
+            Fetch::Accumulator => Some(self.acc.get()),
+            Fetch::Memory => Some(self.mem.read()),
+            Fetch::Expansion => {
+                let select = self.expansion_select;
+                self.expansion.as_mut().map(|bus| bus.read(select, cycles))
+            }
         }
     }
 
@@ -66,27 +378,81 @@ impl Emulator {
             Destination::Accumulator => self.flag_1 = self.acc.set(value),
             Destination::AccumulatorPlus => self.flag_carry = self.acc.add(value),
             Destination::AccumulatorNand => self.acc.nand(value),
-            Destination::Led => self.led.set(value),
+            Destination::Led => {
+                self.led.set(value);
+                if let Some(trace) = &mut self.led_trace {
+                    trace.record(self.cycles, value);
+                }
+            }
             Destination::Memory => self.mem.write(value),
             Destination::MemAddressLo => self.mem.latch_low(value),
             Destination::MemAddressHi => self.mem.latch_high(value),
             Destination::CarrySet => self.flag_carry = true,
             Destination::CarryReset => self.flag_carry = false,
-            _ => todo!("Serial and expansion"),
+            Destination::Spare(spare) => {
+                if let (SpareDestinations::Expansion, Some(bus)) =
+                    (self.spare_destinations, &mut self.expansion)
+                {
+                    bus.spare(spare, value);
+                }
+            }
+            Destination::Serial => {
+                if self.serial_out.len() == SERIAL_LIMIT {
+                    self.serial_out.pop_front();
+                }
+                self.serial_out.push_back(value);
+            }
+            Destination::ExpansionSelect => self.expansion_select = value,
         }
     }
 }
 
 pub type Word = u8;
 
+/// Why the emulator couldn't go on, with the address, byte and decoding of the instruction at
+/// fault
 #[derive(Debug, Clone, Error)]
 pub enum EmulatorError {
-    #[error(transparent)]
-    Decoder(#[from] decoder::DecoderError), // Derive From?
-    #[error("Illegal instruction")]
-    Illegal(Operation),
+    #[error("{error} at {pc:04X}")]
+    Decoder {
+        pc: u16,
+        /// `None` if `pc` is past the end of the ROM
+        opcode: Option<Word>,
+        error: decoder::DecoderError,
+    },
+    #[error("Illegal instruction \"{op}\" at {pc:04X} (opcode {opcode:02X})")]
+    Illegal {
+        pc: u16,
+        opcode: Word,
+        op: Operation,
+    },
     #[error("Missing initial NOP")]
     MissingNop,
+    #[error("Unassigned destination in \"{op}\" at {pc:04X} (opcode {opcode:02X})")]
+    UnassignedDestination {
+        pc: u16,
+        opcode: Word,
+        op: Operation,
+    },
+    #[error("No expansion bus attached for \"{op}\" at {pc:04X} (opcode {opcode:02X})")]
+    NoExpansionBus {
+        pc: u16,
+        opcode: Word,
+        op: Operation,
+    },
+}
+
+impl EmulatorError {
+    /// Address of the instruction at fault
+    pub fn pc(&self) -> Option<u16> {
+        match self {
+            EmulatorError::Decoder { pc, .. }
+            | EmulatorError::Illegal { pc, .. }
+            | EmulatorError::UnassignedDestination { pc, .. }
+            | EmulatorError::NoExpansionBus { pc, .. } => Some(*pc),
+            EmulatorError::MissingNop => None,
+        }
+    }
 }
 
 #[derive(Debug, Default)]
@@ -197,6 +563,20 @@ impl Memory {
         let addr = self.address() as usize;
         self.values[addr] = value;
     }
+
+    /// Read `address` without going through the latches
+    pub fn peek(&self, address: u16) -> Word {
+        self.values.get(address as usize).copied().unwrap_or(0)
+    }
+
+    /// Write `address` without going through the latches
+    pub fn poke(&mut self, address: u16, value: Word) {
+        let address = address as usize;
+        if address >= self.values.len() {
+            self.values.resize_with(address + 1, || 0);
+        }
+        self.values[address] = value;
+    }
 }
 
 #[cfg(test)]
@@ -226,6 +606,109 @@ mod tests {
         assert_eq!(acc.get(), 0x4F);
     }
 
+    #[test]
+    fn test_reset() {
+        assert!(matches!(
+            Emulator::from_program(vec![0b11_1001_00, 0x00].into_boxed_slice()),
+            Err(EmulatorError::MissingNop)
+        ));
+
+        let reset = Reset {
+            vector: 0x0002,
+            acc: 0x5F,
+            flag_carry: true,
+            skip_first_fetch: false,
+            ..Default::default()
+        };
+        let program = vec![0x00, 0x00, 0b01_1001_00].into_boxed_slice();
+        let mut emu = Emulator::with_reset(program, reset).unwrap();
+        assert_eq!(emu.pc.get(), 0x0002);
+        assert!(emu.flag_carry);
+        emu.step().unwrap();
+        assert_eq!(emu.led.get(), 0x5F);
+        emu.reset().unwrap();
+        assert_eq!(emu.pc.get(), 0x0002);
+        assert_eq!(emu.cycles(), 0);
+    }
+
+    #[test]
+    fn test_errors() {
+        // ACC -> LED, then 05 -> RAM, which the assembler won't produce
+        let program = vec![0b01_0011_00, 0b01_1001_00, 0b11_0000_00, 0x05];
+        let mut emu = Emulator::from_program(program.into_boxed_slice()).unwrap();
+        emu.enable_history(4);
+        emu.step().unwrap();
+        emu.step().unwrap();
+        let error = emu.step().unwrap_err();
+        assert!(matches!(
+            &error,
+            EmulatorError::Illegal {
+                pc: 0x0002,
+                opcode: 0b11_0000_00,
+                op: Operation {
+                    src: Source::Operand(0x05),
+                    dest: Destination::Memory,
+                    ..
+                },
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Illegal instruction \"05 -> RAM\" at 0002 (opcode C0)"
+        );
+        // Nothing changed, so the failed step can't be undone
+        assert_eq!(emu.pc.get(), 0x0002);
+        assert_eq!(emu.cycles(), 2);
+        assert_eq!(emu.history.len(), 2);
+
+        // Running off the end, and an operand missing at the end
+        for (program, opcode) in &[
+            (vec![0b01_0011_00], None),
+            (vec![0b01_0011_00, 0b11_1001_00], Some(0xE4)),
+        ] {
+            let mut emu = Emulator::from_program(program.clone().into_boxed_slice()).unwrap();
+            emu.predecode();
+            emu.step().unwrap();
+            let error = emu.step().unwrap_err();
+            assert!(matches!(
+                error,
+                EmulatorError::Decoder {
+                    pc: 0x0001,
+                    opcode: o,
+                    error: decoder::DecoderError::InvalidRead,
+                } if o == *opcode
+            ));
+            assert_eq!(error.pc(), Some(0x0001));
+        }
+
+        // EXP -> ACC with nothing on the expansion bus leaves the state alone
+        let program = vec![0b01_0011_00, 0b00_0011_00];
+        let mut emu = Emulator::from_program(program.into_boxed_slice()).unwrap();
+        emu.step().unwrap();
+        assert!(matches!(
+            emu.step(),
+            Err(EmulatorError::NoExpansionBus { pc: 0x0001, .. })
+        ));
+        assert_eq!(emu.pc.get(), 0x0001);
+        assert_eq!(emu.cycles(), 1);
+    }
+
+    #[test]
+    fn test_serial_out() {
+        let program = assembler::assemble("loop:\n'x' -> Serial.out\nlo@loop -> PC").unwrap();
+        let mut emu = Emulator::from_program(program.into_boxed_slice()).unwrap();
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.take_serial(), b"x");
+        assert!(emu.take_serial().is_empty());
+
+        // Only the newest bytes are kept when nothing takes them
+        for _ in 0..SERIAL_LIMIT * 3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.take_serial().len(), SERIAL_LIMIT);
+    }
+
     #[test]
     fn test_memory() {
         let mut mem = Memory::default();
@@ -1,18 +1,182 @@
-use anyhow::{bail, Result};
-use std::fs;
+use anyhow::{bail, Context, Result};
+use common::args::Args;
+use common::{Operation, Reset};
+use emulator::expansion::SpareDestinations;
+use emulator::profile::{parse_source_map, parse_symbols, SourceLines};
+use emulator::serial::{spawn_reader, SerialReceiver};
+use emulator::timing::Throttle;
 use emulator::Emulator;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::num::NonZeroU64;
+use std::sync::mpsc::Receiver;
+
+const USAGE: &str = "Usage: <file_name.bin> [--steps <count>] [--profile <report.txt>] \
+    [--symbols <file.sym>] [--source-map <file.map>] [--clock <hz>] [--realtime] [--trace] \
+    [--reset-vector <hex>] [--no-dummy-nop] [--spare-destinations <error|nop|expansion>] \
+    [--serial <-|file|tcp:host:port>] [--baud <rate>] [--vcd <leds.vcd>] [--signals <cpu.vcd>]";
+
+/// Open serial input from stdin (`-`), a TCP connection (`tcp:host:port`) or a file
+fn serial_input(source: &str) -> Result<Receiver<u8>> {
+    Ok(if source == "-" {
+        spawn_reader(io::stdin())
+    } else if let Some(address) = source.strip_prefix("tcp:") {
+        let stream =
+            TcpStream::connect(address).with_context(|| format!("Connecting to {}", address))?;
+        spawn_reader(stream)
+    } else {
+        spawn_reader(File::open(source).with_context(|| format!("Opening {}", source))?)
+    })
+}
+
+/// One line of `--trace`: the instruction that ran and the state it left behind
+fn trace_line(emulator: &Emulator, pc: u16, op: &Operation) -> String {
+    format!(
+        "{:04X}  {:<28} ACC {:02X}  1:{} carry:{}  cycles {}",
+        pc,
+        op.to_string(),
+        emulator.acc.get(),
+        emulator.flag_1 as u8,
+        emulator.flag_carry as u8,
+        emulator.cycles()
+    )
+}
 
 fn main() -> Result<()> {
-    let mut args = std::env::args().skip(1);
-    let path = match args.next() {
+    let mut args = Args::from_env();
+    let mut path = None;
+    let mut max_steps = None;
+    let mut profile_path = None;
+    let mut symbols_path = None;
+    let mut source_map_path = None;
+    let mut clock_hz = None;
+    let mut realtime = false;
+    let mut trace = false;
+    let mut reset = Reset::default();
+    let mut spare_destinations = SpareDestinations::default();
+    let mut serial = None;
+    let mut baud = 9600;
+    let mut vcd_path = None;
+    let mut signals_path = None;
+    while let Some(arg) = args.next() {
+        if args.reset(&arg, &mut reset)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--steps" => max_steps = Some(args.parse::<u64>(&arg)?),
+            "--profile" => profile_path = Some(args.value(&arg)?),
+            "--symbols" => symbols_path = Some(args.value(&arg)?),
+            "--source-map" => source_map_path = Some(args.value(&arg)?),
+            "--clock" => clock_hz = Some(args.parse::<NonZeroU64>(&arg)?),
+            "--realtime" => realtime = true,
+            "--trace" => trace = true,
+            "--spare-destinations" => spare_destinations = args.parse(&arg)?,
+            "--serial" => serial = Some(args.value(&arg)?),
+            "--vcd" => vcd_path = Some(args.value(&arg)?),
+            "--signals" => signals_path = Some(args.value(&arg)?),
+            "--baud" => baud = args.parse::<u64>(&arg)?,
+            _ if path.is_none() => path = Some(arg),
+            _ => bail!(USAGE),
+        }
+    }
+    let path = match path {
         Some(v) => v,
-        None => bail!("Usage: <file_name.s>"),
+        None => bail!(USAGE),
     };
+    // The dumps are only written once the run ends, so make sure it does
+    if vcd_path.is_some() && max_steps.is_none() {
+        bail!("--vcd needs --steps to say when to stop and write it");
+    }
+    if signals_path.is_some() && max_steps.is_none() {
+        bail!("--signals needs --steps to say when to stop and write it");
+    }
+    let symbols = match symbols_path {
+        Some(path) => parse_symbols(&fs::read_to_string(path)?)?,
+        None => Vec::new(),
+    };
+    let source = match source_map_path {
+        Some(path) => parse_source_map(&fs::read_to_string(path)?)?,
+        None => SourceLines::new(),
+    };
+
     let program = fs::read(path)?;
-    let mut emulator = Emulator::from_program(program.into_boxed_slice())?;
-    loop {
-        dbg!(&emulator);
-        emulator.step()?;
+    let mut emulator = Emulator::with_reset(program.into_boxed_slice(), reset)?;
+    emulator.predecode();
+    emulator.spare_destinations = spare_destinations;
+    if profile_path.is_some() {
+        emulator.enable_profiling();
+    }
+    if vcd_path.is_some() {
+        emulator.enable_led_trace();
+    }
+    if signals_path.is_some() {
+        emulator.enable_signal_trace();
+    }
+    if let Some(hz) = clock_hz {
+        emulator.timing.clock_hz = hz;
+    }
+    if baud == 0 {
+        bail!("Baud rate must be non-zero");
+    }
+    if spare_destinations == SpareDestinations::Expansion && serial.is_none() {
+        bail!(
+            "--spare-destinations expansion needs a device on the expansion bus, such as --serial"
+        );
+    }
+    if let Some(source) = &serial {
+        let input = serial_input(source)?;
+        emulator.expansion = Some(Box::new(SerialReceiver::new(input, baud, &emulator.timing)));
+    }
+    let mut throttle = Throttle::new(emulator.cycles());
+    let mut stdout = io::stdout();
+
+    let mut steps = 0;
+    let result = loop {
+        if max_steps == Some(steps) {
+            break Ok(());
+        }
+        if realtime {
+            throttle.wait(emulator.cycles(), &emulator.timing);
+        }
+        let pc = emulator.pc.get();
+        if let Err(e) = emulator.step() {
+            break Err(e);
+        }
+        if trace {
+            if let Ok((op, _)) = emulator.decode(pc) {
+                eprintln!("{}", trace_line(&emulator, pc, &op));
+            }
+        }
+        let serial = emulator.take_serial();
+        if !serial.is_empty() {
+            stdout.write_all(&serial)?;
+            stdout.flush()?;
+        }
+        steps += 1;
+    };
+
+    if let (Some(path), Some(trace)) = (vcd_path, &emulator.led_trace) {
+        if trace.is_full() {
+            eprintln!("The LED trace filled up, so {} stops early", path);
+        }
+        fs::write(path, trace.waveform(&emulator.timing).to_string())?;
+    }
+    if let (Some(path), Some(trace)) = (signals_path, &emulator.signal_trace) {
+        if trace.is_full() {
+            eprintln!("The signal trace filled up, so {} stops early", path);
+        }
+        fs::write(path, trace.waveform(&emulator.timing).to_string())?;
+    }
+    if let (Some(path), Some(profile)) = (profile_path, &emulator.profile) {
+        fs::write(path, profile.report(&emulator.program, &symbols, &source))?;
+    }
+    if let Err(e) = result {
+        eprintln!("Stopped after {} steps: {}", steps, e);
+        if let Some(location) = e.pc().and_then(|pc| source.get(&pc)) {
+            eprintln!("  at {}", location);
+        }
+        std::process::exit(1);
     }
-    //Ok(())
+    Ok(())
 }
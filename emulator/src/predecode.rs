@@ -0,0 +1,147 @@
+use crate::decoder::read_micro_op;
+use common::*;
+use std::fmt;
+
+/// Where a micro-op gets its data from. Unlike `Source` this never carries a label, so it's `Copy`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fetch {
+    Expansion,
+    Accumulator,
+    Memory,
+    Operand(u8),
+}
+
+/// A fully decoded instruction, ready to execute without touching the ROM
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MicroOp {
+    pub fetch: Fetch,
+    pub dest: Destination,
+    pub cond_1: bool,
+    pub cond_carry: bool,
+    /// Length of the instruction in bytes
+    pub length: u8,
+}
+
+impl MicroOp {
+    pub fn source(&self) -> Source {
+        match self.fetch {
+            Fetch::Expansion => Source::Expansion,
+            Fetch::Accumulator => Source::Accumulator,
+            Fetch::Memory => Source::Memory,
+            Fetch::Operand(value) => Source::Operand(value),
+        }
+    }
+
+    pub fn operation(&self) -> Operation {
+        Operation {
+            src: self.source(),
+            dest: self.dest,
+            cond_1: self.cond_1,
+            cond_carry: self.cond_carry,
+        }
+    }
+}
+
+/// The ROM decoded once up front, with one entry per address since a jump may land anywhere.
+/// Addresses that don't hold a valid instruction are `None` so the caller can fall back to the
+/// decoder for a proper error.
+#[derive(Default, Clone)]
+pub struct Predecoded {
+    ops: Vec<Option<MicroOp>>,
+}
+
+/// The table is as long as the ROM, so only say how big it is
+impl fmt::Debug for Predecoded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Predecoded({} addresses)", self.ops.len())
+    }
+}
+
+impl Predecoded {
+    pub fn new(program: &[u8]) -> Self {
+        Self {
+            ops: (0..program.len())
+                .map(|pc| Self::decode(program, pc))
+                .collect(),
+        }
+    }
+
+    fn decode(program: &[u8], pc: usize) -> Option<MicroOp> {
+        read_micro_op(program, pc).ok()
+    }
+
+    #[inline]
+    pub fn get(&self, pc: u16) -> Option<MicroOp> {
+        self.ops.get(pc as usize).copied().flatten()
+    }
+
+    /// Re-decode every instruction that may include the byte at `address`
+    pub fn invalidate(&mut self, program: &[u8], address: usize) {
+        for pc in address.saturating_sub(1)..=address {
+            if let Some(op) = self.ops.get_mut(pc) {
+                *op = Self::decode(program, pc);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::read_operation;
+    use crate::Emulator;
+
+    const PROGRAM: &str = "
+00 -> ACC
+loop:
+01 -> ACC.plus
+ACC -> LED
+lo@loop -> PC";
+
+    #[test]
+    fn test_predecode_matches_decoder() {
+        let program = assembler::assemble(PROGRAM).unwrap();
+        let predecoded = Predecoded::new(&program);
+        for pc in 0..program.len() {
+            let expected = read_operation(&program, pc).ok();
+            let op = predecoded.get(pc as u16);
+            assert_eq!(op.map(|op| op.operation()), expected.clone().map(|e| e.0));
+            assert_eq!(op.map(|op| op.length as usize), expected.map(|e| e.1));
+        }
+        assert_eq!(predecoded.get(program.len() as u16), None);
+    }
+
+    #[test]
+    fn test_predecoded_run() {
+        let program = assembler::assemble(PROGRAM).unwrap().into_boxed_slice();
+        let mut slow = Emulator::from_program(program.clone()).unwrap();
+        let mut fast = Emulator::from_program(program).unwrap();
+        fast.predecode();
+        for _ in 0..100 {
+            slow.step().unwrap();
+            fast.step().unwrap();
+            assert_eq!(slow.pc.get(), fast.pc.get());
+            assert_eq!(slow.acc.get(), fast.acc.get());
+            assert_eq!(slow.led.get(), fast.led.get());
+            assert_eq!(slow.cycles(), fast.cycles());
+        }
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let program = assembler::assemble(PROGRAM).unwrap().into_boxed_slice();
+        let mut emu = Emulator::from_program(program).unwrap();
+        emu.predecode();
+        // Change the increment operand from 01 to 05
+        emu.write_rom(4, 0x05);
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.acc.get(), 0x05);
+        // Turn `ACC -> LED` into `ACC -> ACC.nand`
+        emu.write_rom(5, 0b01_0010_00);
+        emu.step().unwrap();
+        assert_eq!(emu.led.get(), 0x00);
+        assert_eq!(emu.acc.get(), 0xFA);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::decoder::read_operation;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Per-address execution counts, indexed by ROM address
+#[derive(Debug, Default, Clone)]
+pub struct Profile {
+    /// Times an instruction was fetched from each address
+    pub fetched: Vec<u64>,
+    /// Times a conditional instruction at each address had its condition pass
+    pub taken: Vec<u64>,
+    /// Times a conditional instruction at each address was skipped
+    pub skipped: Vec<u64>,
+}
+
+/// Labels from the assembler's symbol map, sorted by address
+pub type Symbols = Vec<(u16, String)>;
+
+/// The `file:line` of the instruction starting at each address, from the assembler's source map
+pub type SourceLines = BTreeMap<u16, String>;
+
+impl Profile {
+    pub fn new(rom_size: usize) -> Self {
+        Self {
+            fetched: vec![0; rom_size],
+            taken: vec![0; rom_size],
+            skipped: vec![0; rom_size],
+        }
+    }
+
+    pub fn record(&mut self, pc: u16, conditional: bool, executed: bool) {
+        let pc = pc as usize;
+        if pc >= self.fetched.len() {
+            return;
+        }
+        self.fetched[pc] += 1;
+        if conditional {
+            if executed {
+                self.taken[pc] += 1;
+            } else {
+                self.skipped[pc] += 1;
+            }
+        }
+    }
+
+    /// Take back a `record` when the step is undone
+    pub fn unrecord(&mut self, pc: u16, conditional: bool, executed: bool) {
+        let pc = pc as usize;
+        if pc >= self.fetched.len() {
+            return;
+        }
+        self.fetched[pc] = self.fetched[pc].saturating_sub(1);
+        if conditional {
+            let count = if executed {
+                &mut self.taken[pc]
+            } else {
+                &mut self.skipped[pc]
+            };
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Returns (instructions fetched at least once, instructions in the ROM)
+    pub fn coverage(&self, program: &[u8]) -> (usize, usize) {
+        let addresses = instruction_addresses(program);
+        let covered = addresses
+            .iter()
+            .filter(|addr| self.fetched[**addr] > 0)
+            .count();
+        (covered, addresses.len())
+    }
+
+    /// Fetches per label, summing every address from the label up to the next one. Labels sharing
+    /// an address are joined with commas.
+    pub fn by_label(&self, program: &[u8], symbols: &Symbols) -> Vec<LabelProfile> {
+        let mut profiles: Vec<LabelProfile> = Vec::new();
+        for (addr, label) in symbols {
+            match profiles.last_mut() {
+                Some(last) if last.address == *addr => {
+                    last.label.push(',');
+                    last.label.push_str(label);
+                }
+                _ => profiles.push(LabelProfile {
+                    label: label.clone(),
+                    address: *addr,
+                    fetched: 0,
+                    instructions: 0,
+                    covered: 0,
+                }),
+            }
+        }
+
+        for addr in instruction_addresses(program) {
+            let owner = profiles
+                .iter_mut()
+                .rev()
+                .find(|p| p.address as usize <= addr);
+            if let Some(owner) = owner {
+                owner.fetched += self.fetched[addr];
+                owner.instructions += 1;
+                if self.fetched[addr] > 0 {
+                    owner.covered += 1;
+                }
+            }
+        }
+        profiles
+    }
+
+    /// A report with the per-label profile followed by an annotated listing of the whole ROM
+    pub fn report(&self, program: &[u8], symbols: &Symbols, source: &SourceLines) -> String {
+        let mut out = String::new();
+        let (covered, total) = self.coverage(program);
+        let taken: u64 = self.taken.iter().sum();
+        let skipped: u64 = self.skipped.iter().sum();
+        writeln!(
+            out,
+            "Instructions fetched: {}",
+            self.fetched.iter().sum::<u64>()
+        )
+        .unwrap();
+        writeln!(out, "Conditionals taken: {}, skipped: {}", taken, skipped).unwrap();
+        writeln!(out, "Coverage: {}/{} instructions", covered, total).unwrap();
+
+        let mut labels = self.by_label(program, symbols);
+        labels.sort_by(|a, b| b.fetched.cmp(&a.fetched).then(a.address.cmp(&b.address)));
+        writeln!(
+            out,
+            "\n{:>12} {:>9}  {:>4}  label",
+            "fetched", "covered", "addr"
+        )
+        .unwrap();
+        for label in labels {
+            writeln!(
+                out,
+                "{:>12} {:>4}/{:<4}  {:04X}  {}",
+                label.fetched, label.covered, label.instructions, label.address, label.label
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "\n{:>12} {:>12}  {:>4}  instruction",
+            "fetched", "taken/skip", "addr"
+        )
+        .unwrap();
+        let mut pc = 0;
+        while pc < program.len() {
+            for (_, label) in symbols.iter().filter(|(addr, _)| *addr as usize == pc) {
+                writeln!(out, "{:>12} {:>12}  {:>4}  {}:", "", "", "", label).unwrap();
+            }
+            let marker = if self.fetched[pc] == 0 { "-" } else { "" };
+            let conditional = if self.taken[pc] + self.skipped[pc] > 0 {
+                format!("{}/{}", self.taken[pc], self.skipped[pc])
+            } else {
+                String::new()
+            };
+            match read_operation(program, pc) {
+                Ok((op, advance)) => {
+                    let op = match source.get(&(pc as u16)) {
+                        Some(location) => format!("{:<32} ; {}", op.to_string(), location),
+                        None => op.to_string(),
+                    };
+                    writeln!(
+                        out,
+                        "{:>12} {:>12}  {:04X}  {}",
+                        format!("{}{}", marker, self.fetched[pc]),
+                        conditional,
+                        pc,
+                        op
+                    )
+                    .unwrap();
+                    pc += advance;
+                }
+                Err(e) => {
+                    writeln!(
+                        out,
+                        "{:>12} {:>12}  {:04X}  {:02X} ({})",
+                        "", "", pc, program[pc], e
+                    )
+                    .unwrap();
+                    pc += 1;
+                }
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelProfile {
+    pub label: String,
+    pub address: u16,
+    /// Total fetches of instructions belonging to this label
+    pub fetched: u64,
+    /// Number of instructions belonging to this label
+    pub instructions: usize,
+    /// Number of those instructions that were fetched at least once
+    pub covered: usize,
+}
+
+/// Addresses of every instruction when the ROM is read linearly from the start
+pub fn instruction_addresses(program: &[u8]) -> Vec<usize> {
+    let mut addresses = Vec::new();
+    let mut pc = 0;
+    while pc < program.len() {
+        addresses.push(pc);
+        pc += read_operation(program, pc)
+            .map(|(_, advance)| advance)
+            .unwrap_or(1);
+    }
+    addresses
+}
+
+/// Parse a symbol map written by `assembler --symbols`
+pub fn parse_symbols(text: &str) -> Result<Symbols> {
+    let mut symbols = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let mut parts = line.split_whitespace();
+        let (addr, label) = match (parts.next(), parts.next()) {
+            (Some(addr), Some(label)) => (addr, label),
+            (None, _) => continue,
+            _ => bail!("Malformed symbol on line {}", line_number + 1),
+        };
+        let addr = u16::from_str_radix(addr, 16)
+            .with_context(|| format!("Bad address on line {}", line_number + 1))?;
+        symbols.push((addr, label.to_string()));
+    }
+    symbols.sort();
+    Ok(symbols)
+}
+
+/// Parse a source map written by `assembler --source-map`, keeping the instruction starts
+pub fn parse_source_map(text: &str) -> Result<SourceLines> {
+    let mut source = SourceLines::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let mut parts = line.splitn(3, ' ');
+        let (addr, kind, location) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(addr), Some(kind), Some(location)) => (addr, kind, location),
+            (Some(""), None, None) => continue,
+            _ => bail!("Malformed source map entry on line {}", line_number + 1),
+        };
+        let addr = u16::from_str_radix(addr, 16)
+            .with_context(|| format!("Bad address on line {}", line_number + 1))?;
+        if kind == "opcode" {
+            source.insert(addr, location.to_string());
+        }
+    }
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Emulator;
+
+    fn run(text: &str, steps: usize) -> (Emulator, Symbols, SourceLines) {
+        let assembly = assembler::assemble_for(text, &Default::default()).unwrap();
+        let symbols = parse_symbols(&assembler::format_symbols(&assembly.symbols)).unwrap();
+        let source =
+            parse_source_map(&assembler::format_source_map(&assembly.source_map, "a.s")).unwrap();
+        let mut emu = Emulator::from_program(assembly.bytes.into_boxed_slice()).unwrap();
+        emu.enable_profiling();
+        for _ in 0..steps {
+            emu.step().unwrap();
+        }
+        (emu, symbols, source)
+    }
+
+    #[test]
+    fn test_profile() {
+        let (emu, symbols, source) = run(
+            "
+00 -> ACC
+delay:
+40 -> ACC.plus
+lo@done -> PC : if_carry
+lo@delay -> PC
+done:
+ACC -> LED
+dead:
+ACC -> LED",
+            2 + 3 * 3 + 2 + 1,
+        );
+        let profile = emu.profile.as_ref().unwrap();
+        assert_eq!(profile.fetched[3], 4);
+        assert_eq!(profile.taken[5], 1);
+        assert_eq!(profile.skipped[5], 3);
+        assert_eq!(profile.coverage(&emu.program), (6, 7));
+
+        let labels = profile.by_label(&emu.program, &symbols);
+        assert_eq!(labels[0].label, "delay");
+        assert_eq!(labels[0].fetched, 11);
+        assert_eq!(labels[0].instructions, 3);
+        assert_eq!(labels[2].label, "dead");
+        assert_eq!(labels[2].covered, 0);
+
+        assert_eq!(source.get(&3).unwrap(), "a.s:4");
+        let report = profile.report(&emu.program, &symbols, &source);
+        assert!(report.contains("Coverage: 6/7 instructions"));
+        assert!(report
+            .lines()
+            .any(|line| line.contains("40 -> ACC.plus") && line.ends_with("; a.s:4")));
+        assert!(report.contains("dead:"));
+    }
+
+    #[test]
+    fn test_parse_symbols() {
+        assert_eq!(
+            parse_symbols("0003 b\n0001 a\n\n").unwrap(),
+            vec![(1, "a".to_string()), (3, "b".to_string())]
+        );
+        assert!(parse_symbols("zz a").is_err());
+        assert!(parse_symbols("0001").is_err());
+    }
+
+    #[test]
+    fn test_parse_source_map() {
+        let source =
+            parse_source_map("0001 opcode my file.s:3\n0002 operand my file.s:3\n").unwrap();
+        assert_eq!(
+            source.into_iter().collect::<Vec<_>>(),
+            vec![(1, "my file.s:3".to_string())]
+        );
+        assert!(parse_source_map("0001 opcode").is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! A serial receiver on the expansion bus, so that programs can read input a byte at a time
+
+use crate::expansion::ExpansionBus;
+use crate::timing::Timing;
+use crate::Word;
+use common::SpareDestination;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+/// `EXP.sel` value that makes `EXP` read the status flags
+pub const STATUS: Word = 0x00;
+/// `EXP.sel` value that makes `EXP` read the oldest received byte, or 0 if there isn't one
+pub const DATA: Word = 0x01;
+
+/// Status flag set while there is a byte to read
+pub const DATA_AVAILABLE: Word = 0b0000_0001;
+/// Status flag set when a byte was lost because the buffer was full. Reading the status clears
+/// it.
+pub const OVERRUN: Word = 0b0000_0010;
+
+/// Read bytes from `reader` on another thread, so the emulator never blocks waiting for input
+pub fn spawn_reader<R: Read + Send + 'static>(mut reader: R) -> Receiver<u8> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut buffer = [0; 256];
+        while let Ok(length @ 1..=256) = reader.read(&mut buffer) {
+            for byte in &buffer[..length] {
+                if sender.send(*byte).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+/// A UART receiving 8N1 frames at a fixed baud rate into a small FIFO. Bytes take as long to
+/// arrive as they would on the wire, starting no earlier than the last time the receiver saw the
+/// line idle.
+#[derive(Debug)]
+pub struct SerialReceiver {
+    input: Receiver<u8>,
+    /// The byte being shifted in and the cycle it finishes arriving on
+    arriving: Option<(u8, u64)>,
+    fifo: VecDeque<u8>,
+    capacity: usize,
+    overrun: bool,
+    /// Clock cycles taken by a start bit, 8 data bits and a stop bit
+    frame_cycles: u64,
+    /// When the last byte finished arriving
+    line_free: u64,
+    /// When the input was last found empty
+    idle_since: u64,
+}
+
+impl SerialReceiver {
+    /// Bytes the FIFO holds before overrunning
+    pub const DEFAULT_CAPACITY: usize = 16;
+
+    pub fn new(input: Receiver<u8>, baud: u64, timing: &Timing) -> Self {
+        Self {
+            input,
+            arriving: None,
+            fifo: VecDeque::new(),
+            capacity: Self::DEFAULT_CAPACITY,
+            overrun: false,
+            frame_cycles: (timing.clock_hz.get() * 10 / baud.max(1)).max(1),
+            line_free: 0,
+            idle_since: 0,
+        }
+    }
+
+    /// Move every byte that has finished arriving by `cycles` into the FIFO
+    fn update(&mut self, cycles: u64) {
+        loop {
+            if self.arriving.is_none() {
+                match self.input.try_recv() {
+                    Ok(byte) => {
+                        let done = self.line_free.max(self.idle_since) + self.frame_cycles;
+                        self.arriving = Some((byte, done));
+                        self.line_free = done;
+                    }
+                    Err(_) => {
+                        self.idle_since = cycles;
+                        return;
+                    }
+                }
+            }
+            match self.arriving {
+                Some((byte, done)) if done <= cycles => {
+                    if self.fifo.len() < self.capacity {
+                        self.fifo.push_back(byte);
+                    } else {
+                        self.overrun = true;
+                    }
+                    self.arriving = None;
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+impl ExpansionBus for SerialReceiver {
+    fn spare(&mut self, _spare: SpareDestination, _value: Word) {}
+
+    fn read(&mut self, select: Word, cycles: u64) -> Word {
+        self.update(cycles);
+        match select {
+            STATUS => {
+                let mut status = 0;
+                if !self.fifo.is_empty() {
+                    status |= DATA_AVAILABLE;
+                }
+                if self.overrun {
+                    status |= OVERRUN;
+                }
+                self.overrun = false;
+                status
+            }
+            DATA => self.fifo.pop_front().unwrap_or(0),
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Emulator;
+    use std::num::NonZeroU64;
+
+    /// A receiver at 1 byte per 100 cycles, holding `input`
+    fn receiver(input: &[u8]) -> SerialReceiver {
+        let (sender, receiver) = channel();
+        for byte in input {
+            sender.send(*byte).unwrap();
+        }
+        let timing = Timing {
+            clock_hz: NonZeroU64::new(10_000).unwrap(),
+            ..Default::default()
+        };
+        SerialReceiver::new(receiver, 1_000, &timing)
+    }
+
+    #[test]
+    fn test_receiver() {
+        let mut serial = receiver(b"hi");
+        assert_eq!(serial.read(STATUS, 99), 0);
+        assert_eq!(serial.read(STATUS, 100), DATA_AVAILABLE);
+        assert_eq!(serial.read(DATA, 150), b'h');
+        assert_eq!(serial.read(STATUS, 150), 0);
+        assert_eq!(serial.read(DATA, 200), b'i');
+        assert_eq!(serial.read(DATA, 1000), 0);
+
+        let mut serial = receiver(&[0; SerialReceiver::DEFAULT_CAPACITY + 1]);
+        assert_eq!(serial.read(STATUS, 10_000), DATA_AVAILABLE | OVERRUN);
+        assert_eq!(serial.read(STATUS, 10_000), DATA_AVAILABLE);
+    }
+
+    #[test]
+    fn test_echo() {
+        let program = assembler::assemble(
+            "
+wait:
+    00 -> EXP.sel
+    EXP -> ACC
+    01 -> ACC.nand
+    ACC -> ACC
+    lo@wait -> PC : if_1
+    01 -> EXP.sel
+    EXP -> Serial.out
+    lo@wait -> PC",
+        )
+        .unwrap();
+        let mut emu = Emulator::from_program(program.into_boxed_slice()).unwrap();
+        emu.expansion = Some(Box::new(receiver(b"ok")));
+        for _ in 0..500 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.take_serial(), b"ok");
+    }
+}
@@ -0,0 +1,129 @@
+use std::num::NonZeroU64;
+use std::time::{Duration, Instant};
+
+/// How long instructions take on the board
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    /// Clock frequency in Hz
+    pub clock_hz: NonZeroU64,
+    /// Cycles taken by a single byte move
+    pub move_cycles: u64,
+    /// Cycles taken by a move with an operand byte
+    pub operand_cycles: u64,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            clock_hz: NonZeroU64::new(1_000_000).unwrap(),
+            move_cycles: 1,
+            operand_cycles: 2,
+        }
+    }
+}
+
+impl Timing {
+    /// Cycles taken by an instruction `length` bytes long. Skipped instructions still have to be
+    /// fetched, so they take just as long.
+    pub fn cycles(&self, length: usize) -> u64 {
+        if length > 1 {
+            self.operand_cycles
+        } else {
+            self.move_cycles
+        }
+    }
+
+    /// Wall clock time taken by `cycles` clock cycles
+    pub fn duration(&self, cycles: u64) -> Duration {
+        Duration::from_nanos((cycles as u128 * 1_000_000_000 / self.clock_hz.get() as u128) as u64)
+    }
+}
+
+/// Keeps the emulator from running faster than the real board
+#[derive(Debug)]
+pub struct Throttle {
+    start: Instant,
+    start_cycles: u64,
+}
+
+impl Throttle {
+    /// Don't bother sleeping for less than this; the OS can't do it accurately anyway
+    const MIN_SLEEP: Duration = Duration::from_millis(1);
+
+    /// Start measuring real time from now, at the given cycle count
+    pub fn new(cycles: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            start_cycles: cycles,
+        }
+    }
+
+    /// Sleep until real time catches up with `cycles`
+    pub fn wait(&mut self, cycles: u64, timing: &Timing) {
+        if let Some(delay) = self.delay(cycles, timing, self.start.elapsed()) {
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// How long to sleep for real time to catch up with `cycles`, `elapsed` after starting
+    pub fn delay(&self, cycles: u64, timing: &Timing, elapsed: Duration) -> Option<Duration> {
+        let target = timing.duration(cycles.saturating_sub(self.start_cycles));
+        if target > elapsed + Self::MIN_SLEEP {
+            Some(target - elapsed)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Emulator;
+
+    #[test]
+    fn test_cycle_count() {
+        let program = assembler::assemble(
+            "
+ACC -> LED
+5F -> LED
+00 -> ACC
+lo@skip -> PC : if_1
+skip:",
+        )
+        .unwrap();
+        let mut emu = Emulator::from_program(program.into_boxed_slice()).unwrap();
+        emu.timing.operand_cycles = 3;
+        for _ in 0..5 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.cycles(), 1 + 1 + 3 + 3 + 3);
+    }
+
+    #[test]
+    fn test_duration() {
+        let timing = Timing {
+            clock_hz: NonZeroU64::new(4_000).unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(timing.duration(4_000), Duration::from_secs(1));
+        assert_eq!(timing.duration(2), Duration::from_micros(500));
+    }
+
+    #[test]
+    fn test_throttle() {
+        let timing = Timing {
+            clock_hz: NonZeroU64::new(1_000).unwrap(),
+            ..Default::default()
+        };
+        let throttle = Throttle::new(100);
+        let delay =
+            |cycles, elapsed| throttle.delay(cycles, &timing, Duration::from_millis(elapsed));
+        assert_eq!(delay(150, 0), Some(Duration::from_millis(50)));
+        assert_eq!(delay(150, 20), Some(Duration::from_millis(30)));
+        // Too short to sleep for, or already behind
+        assert_eq!(delay(150, 50), None);
+        assert_eq!(delay(150, 60), None);
+        assert_eq!(delay(50, 0), None);
+    }
+}
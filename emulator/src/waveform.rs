@@ -0,0 +1,439 @@
+//! Recording signals over time and writing them as VCD files for waveform viewers
+
+use crate::timing::Timing;
+use crate::Word;
+use std::fmt;
+
+/// A signal in a waveform, `width` bits wide
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signal {
+    pub name: String,
+    pub width: u32,
+}
+
+/// Signals and their changes over time, written as a Value Change Dump by `Display`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Waveform {
+    pub signals: Vec<Signal>,
+    /// Time in nanoseconds, index into `signals` and new value, in time order. `None` is an
+    /// unknown value. The first value of every signal should be at time 0.
+    pub changes: Vec<(u64, usize, Option<u64>)>,
+}
+
+impl Waveform {
+    pub fn add_signal(&mut self, name: &str, width: u32) -> usize {
+        self.signals.push(Signal {
+            name: name.to_string(),
+            width,
+        });
+        self.signals.len() - 1
+    }
+}
+
+/// The short identifier VCD uses for the `index`th signal, made of printable characters
+fn identifier(mut index: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return id;
+        }
+        index -= 1;
+    }
+}
+
+impl fmt::Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "$version 8TTACC emulator $end")?;
+        writeln!(f, "$timescale 1ns $end")?;
+        writeln!(f, "$scope module board $end")?;
+        for (index, signal) in self.signals.iter().enumerate() {
+            writeln!(
+                f,
+                "$var wire {} {} {} $end",
+                signal.width,
+                identifier(index),
+                signal.name
+            )?;
+        }
+        writeln!(f, "$upscope $end")?;
+        writeln!(f, "$enddefinitions $end")?;
+        let mut time = None;
+        for &(at, index, value) in &self.changes {
+            if time != Some(at) {
+                writeln!(f, "#{}", at)?;
+                time = Some(at);
+            }
+            match (self.signals[index].width, value) {
+                (1, Some(value)) => writeln!(f, "{}{}", value & 1, identifier(index))?,
+                (1, None) => writeln!(f, "x{}", identifier(index))?,
+                (_, None) => writeln!(f, "bx {}", identifier(index))?,
+                (width, Some(value)) => writeln!(
+                    f,
+                    "b{:0w$b} {}",
+                    value,
+                    identifier(index),
+                    w = width as usize
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The most changes or instructions a trace records. A full trace stops recording, so a long run
+/// can't use up memory.
+pub const TRACE_LIMIT: usize = 1 << 20;
+
+/// Every value the LEDs have shown, with the cycle it appeared on
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LedTrace {
+    pub changes: Vec<(u64, Word)>,
+}
+
+impl LedTrace {
+    /// Start a trace with the LEDs showing `value` at `cycles`
+    pub fn new(cycles: u64, value: Word) -> Self {
+        Self {
+            changes: vec![(cycles, value)],
+        }
+    }
+
+    /// Record the LEDs being set, ignoring writes that don't change them
+    pub fn record(&mut self, cycles: u64, value: Word) {
+        if self.changes.last().map(|(_, v)| *v) != Some(value) && !self.is_full() {
+            self.changes.push((cycles, value));
+        }
+    }
+
+    /// Whether the trace has reached `TRACE_LIMIT` and stopped recording
+    pub fn is_full(&self) -> bool {
+        self.changes.len() >= TRACE_LIMIT
+    }
+
+    /// Forget changes after `cycles`, when stepping backwards
+    pub fn rewind(&mut self, cycles: u64) {
+        while self.changes.len() > 1 && self.changes.last().unwrap().0 > cycles {
+            self.changes.pop();
+        }
+    }
+
+    /// The LEDs as one 8 bit signal `led` and one signal per LED, `led0` to `led7`, timed by
+    /// the clock in `timing`
+    pub fn waveform(&self, timing: &Timing) -> Waveform {
+        let mut waveform = Waveform::default();
+        let bus = waveform.add_signal("led", 8);
+        let bits = (0..8)
+            .map(|bit| waveform.add_signal(&format!("led{}", bit), 1))
+            .collect::<Vec<_>>();
+        let start = self.changes.first().map_or(0, |(cycles, _)| *cycles);
+        let mut previous: Option<Word> = None;
+        for &(cycles, value) in &self.changes {
+            let time = timing.duration(cycles.saturating_sub(start)).as_nanos() as u64;
+            waveform.changes.push((time, bus, Some(value as u64)));
+            for (bit, signal) in bits.iter().enumerate() {
+                let changed = match previous {
+                    Some(previous) => (previous ^ value) & (1 << bit) != 0,
+                    None => true,
+                };
+                if changed {
+                    waveform
+                        .changes
+                        .push((time, *signal, Some((value >> bit) as u64 & 1)));
+                }
+            }
+            previous = Some(value);
+        }
+        waveform
+    }
+}
+
+/// The registers and control lines of the board while one instruction executes
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Signals {
+    /// Address of the instruction being fetched
+    pub pc: u16,
+    pub pc_latch: Word,
+    pub acc: Word,
+    pub ram_low: Word,
+    pub ram_high: Word,
+    pub flag_1: bool,
+    pub flag_carry: bool,
+    pub led: Word,
+    pub expansion_select: Word,
+    /// The instruction byte, whose bits drive the source, destination and condition select
+    /// lines. `None` when no instruction is executing.
+    pub opcode: Option<Word>,
+    /// Whether the condition passed, so the destination gets written
+    pub write: bool,
+    /// The value moved over the data bus, `None` if the instruction was skipped
+    pub bus: Option<Word>,
+}
+
+impl Signals {
+    /// The same registers with no instruction executing
+    pub fn idle(self) -> Self {
+        Self {
+            opcode: None,
+            write: false,
+            bus: None,
+            ..self
+        }
+    }
+
+    /// The signals on `cycle` of the `cycles` this instruction takes. An operand is fetched on
+    /// the cycle after the opcode, and the destination is written on the last cycle.
+    pub fn on_cycle(self, cycle: u64, cycles: u64) -> Self {
+        let operand = self.opcode.is_some_and(|opcode| opcode >> 6 == 0b11);
+        let last = cycle + 1 >= cycles;
+        Self {
+            pc: self.pc.wrapping_add((operand && cycle > 0) as u16),
+            write: self.write && last,
+            bus: self.bus.filter(|_| last),
+            ..self
+        }
+    }
+}
+
+/// How to get the value of one signal from `Signals`
+type Probe = fn(&Signals) -> Option<u64>;
+
+/// Signals in the order they're written, with their width and how to get their value
+const SIGNALS: [(&str, u32, Probe); 16] = [
+    ("pc", 16, |s| Some(s.pc as u64)),
+    ("pc_latch", 8, |s| Some(s.pc_latch as u64)),
+    ("acc", 8, |s| Some(s.acc as u64)),
+    ("ram_low", 8, |s| Some(s.ram_low as u64)),
+    ("ram_high", 8, |s| Some(s.ram_high as u64)),
+    ("flag_1", 1, |s| Some(s.flag_1 as u64)),
+    ("flag_carry", 1, |s| Some(s.flag_carry as u64)),
+    ("led", 8, |s| Some(s.led as u64)),
+    ("exp_sel", 8, |s| Some(s.expansion_select as u64)),
+    ("opcode", 8, |s| s.opcode.map(u64::from)),
+    ("src_sel", 2, |s| s.opcode.map(|op| (op >> 6) as u64)),
+    ("dest_sel", 4, |s| {
+        s.opcode.map(|op| (op >> 2) as u64 & 0b1111)
+    }),
+    ("cond_1", 1, |s| s.opcode.map(|op| (op >> 1) as u64 & 1)),
+    ("cond_carry", 1, |s| s.opcode.map(|op| op as u64 & 1)),
+    ("write", 1, |s| Some(s.write as u64)),
+    ("bus", 8, |s| s.bus.map(u64::from)),
+];
+
+/// The signals of every instruction executed, for comparing against a model of the hardware.
+/// The waveform has them on every clock cycle, see `Signals::on_cycle`, and registers change on
+/// the cycle after the instruction that writes them.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SignalTrace {
+    /// The cycle the trace started on
+    pub start: u64,
+    /// Each instruction's signals with the cycle it started on
+    pub samples: Vec<(u64, Signals)>,
+    /// The registers after the last instruction and the cycle it finished on
+    pub end: (u64, Signals),
+}
+
+impl SignalTrace {
+    /// Start a trace with the registers in `signals` at `cycles`
+    pub fn new(cycles: u64, signals: Signals) -> Self {
+        Self {
+            start: cycles,
+            samples: Vec::new(),
+            end: (cycles, signals.idle()),
+        }
+    }
+
+    /// Record an instruction running from `start` to `end`, with the registers left as `after`
+    pub fn record(&mut self, start: u64, during: Signals, end: u64, after: Signals) {
+        if !self.is_full() {
+            self.samples.push((start, during));
+            self.end = (end, after.idle());
+        }
+    }
+
+    /// Whether the trace has reached `TRACE_LIMIT` and stopped recording
+    pub fn is_full(&self) -> bool {
+        self.samples.len() >= TRACE_LIMIT
+    }
+
+    /// Forget instructions that started at or after `cycles`, when stepping backwards
+    pub fn rewind(&mut self, cycles: u64) {
+        while let Some(&(start, signals)) = self.samples.last() {
+            if start < cycles {
+                break;
+            }
+            self.samples.pop();
+            self.end = (start, signals.idle());
+        }
+    }
+
+    /// The signals on every cycle, ending with the registers after the last instruction
+    pub fn cycles(&self) -> Vec<(u64, Signals)> {
+        let mut cycles = Vec::new();
+        for (index, &(start, signals)) in self.samples.iter().enumerate() {
+            let end = self
+                .samples
+                .get(index + 1)
+                .map_or(self.end.0, |(next, _)| *next);
+            let length = end.saturating_sub(start).max(1);
+            for cycle in 0..length {
+                cycles.push((start + cycle, signals.on_cycle(cycle, length)));
+            }
+        }
+        cycles.push(self.end);
+        cycles
+    }
+
+    /// Every signal on every cycle, timed by the clock in `timing`
+    pub fn waveform(&self, timing: &Timing) -> Waveform {
+        let mut waveform = Waveform::default();
+        for (name, width, _) in SIGNALS.iter() {
+            waveform.add_signal(name, *width);
+        }
+        let mut previous = [None; SIGNALS.len()];
+        for (index, (cycles, signals)) in self.cycles().iter().enumerate() {
+            let time = timing
+                .duration(cycles.saturating_sub(self.start))
+                .as_nanos() as u64;
+            for (signal, (_, _, value)) in SIGNALS.iter().enumerate() {
+                let value = value(signals);
+                if index == 0 || value != previous[signal] {
+                    waveform.changes.push((time, signal, value));
+                    previous[signal] = value;
+                }
+            }
+        }
+        waveform
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Emulator;
+    use std::num::NonZeroU64;
+
+    #[test]
+    fn test_led_trace() {
+        let program = assembler::assemble(
+            "
+01 -> LED
+01 -> LED
+ACC -> LED
+03 -> LED",
+        )
+        .unwrap();
+        let mut emu = Emulator::from_program(program.into_boxed_slice()).unwrap();
+        // Past the dummy NOP, so the trace doesn't start at 0
+        emu.step().unwrap();
+        emu.enable_led_trace();
+        for _ in 0..4 {
+            emu.step().unwrap();
+        }
+        let trace = emu.led_trace.clone().unwrap();
+        assert_eq!(
+            trace.changes,
+            vec![(1, 0x00), (3, 0x01), (6, 0x00), (8, 0x03)]
+        );
+
+        let timing = Timing {
+            clock_hz: NonZeroU64::new(1_000).unwrap(),
+            ..Default::default()
+        };
+        let vcd = trace.waveform(&timing).to_string();
+        assert!(vcd.contains("$var wire 8 ! led $end\n$var wire 1 \" led0 $end\n"));
+        assert!(vcd.contains("#0\nb00000000 !\n0\"\n0#\n"));
+        assert!(vcd.contains("#2000000\nb00000001 !\n1\"\n#5000000\n"));
+        assert!(vcd.ends_with("#7000000\nb00000011 !\n1\"\n1#\n"));
+
+        emu.enable_history(4);
+        emu.reset().unwrap();
+        emu.enable_led_trace();
+        emu.step().unwrap();
+        emu.step_back();
+        assert_eq!(emu.led_trace.unwrap().changes, vec![(0, 0x00)]);
+    }
+
+    #[test]
+    fn test_signal_trace() {
+        let program = assembler::assemble(
+            "
+05 -> ACC
+ACC -> ACC.plus
+lo@done -> PC : if_carry
+done:",
+        )
+        .unwrap();
+        let mut emu = Emulator::from_program(program.into_boxed_slice()).unwrap();
+        emu.enable_history(4);
+        // Past the dummy NOP, so the trace doesn't start at 0
+        emu.step().unwrap();
+        emu.enable_signal_trace();
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        let trace = emu.signal_trace.clone().unwrap();
+        let during = trace.samples.iter().map(|(_, s)| *s).collect::<Vec<_>>();
+        assert_eq!(trace.samples[1].0, 3);
+        assert_eq!(during[0].opcode, Some(0b11_0011_00));
+        assert_eq!(during[0].bus, Some(0x05));
+        assert_eq!(during[1].acc, 0x05);
+        assert_eq!(during[1].bus, Some(0x05));
+        assert!(!during[2].write);
+        assert_eq!(during[2].bus, None);
+        assert_eq!(trace.end.0, 6);
+        assert_eq!(trace.end.1.acc, 0x0A);
+        assert_eq!(trace.end.1.pc, 0x0006);
+
+        let timing = Timing {
+            clock_hz: NonZeroU64::new(1_000).unwrap(),
+            ..Default::default()
+        };
+        let vcd = trace.waveform(&timing).to_string();
+        assert!(vcd.contains("$var wire 16 ! pc $end\n"));
+        assert!(vcd.contains("$var wire 2 + src_sel $end\n"));
+        assert!(vcd.contains("#0\nb0000000000000001 !\n"));
+        assert!(vcd.contains("b11 +\n"));
+        // The operand is fetched and the destination written on the second cycle
+        assert!(vcd.contains("#1000000\nb0000000000000010 !\n1/\nb00000101 0\n"));
+        assert!(vcd.contains("#3000000\nb0000000000000100 !\n"));
+        assert!(vcd.ends_with("#5000000\nb0000000000000110 !\nbx *\nbx +\nbx ,\nx-\nx.\n"));
+
+        emu.step_back();
+        emu.step_back();
+        let trace = emu.signal_trace.unwrap();
+        assert_eq!(trace.samples.len(), 1);
+        assert_eq!(trace.end, (3, during[1].idle()));
+    }
+
+    #[test]
+    fn test_full_traces() {
+        let mut leds = LedTrace {
+            changes: vec![(0, 0x00); TRACE_LIMIT],
+        };
+        assert!(leds.is_full());
+        leds.record(1, 0x01);
+        assert_eq!(leds.changes.len(), TRACE_LIMIT);
+
+        let mut signals = SignalTrace {
+            samples: vec![(0, Signals::default()); TRACE_LIMIT],
+            ..Default::default()
+        };
+        let after = Signals {
+            acc: 0x01,
+            ..Default::default()
+        };
+        signals.record(1, Signals::default(), 2, after);
+        assert_eq!(signals.samples.len(), TRACE_LIMIT);
+        assert_eq!(signals.end, (0, Signals::default()));
+    }
+
+    #[test]
+    fn test_identifier() {
+        assert_eq!(identifier(0), "!");
+        assert_eq!(identifier(93), "~");
+        assert_eq!(identifier(94), "!!");
+        assert_eq!(identifier(95), "\"!");
+    }
+}
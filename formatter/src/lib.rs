@@ -0,0 +1,330 @@
+use assembler::{
+    explain_error, parse_bracketed, parse_conditional, parse_line, parse_repeat, Error, Statement,
+    Syntax,
+};
+use common::*;
+
+/// Instructions are indented this far, labels not at all
+const INDENT: &str = "    ";
+
+/// Accept anything the assembler can be told to accept
+const SYNTAX: Syntax = Syntax {
+    strict: false,
+    latin1: true,
+};
+
+#[derive(Debug, PartialEq)]
+enum Line {
+    Blank,
+    /// Directives and macro bodies, which are printed as they are
+    Verbatim(String),
+    Comment(String),
+    Label {
+        label: Label,
+        comment: Option<String>,
+    },
+    Operation {
+        src: String,
+        dest: String,
+        conditions: String,
+        comment: Option<String>,
+    },
+}
+
+/// Reprint `text` with instructions indented, arrows, conditions and trailing comments aligned
+/// within each run of consecutive instructions, and conditions in the order `if_carry | if_1`.
+/// Comments, blank lines and preprocessor directives are kept.
+pub fn format(text: &str) -> Result<String, Error> {
+    let lines = parse(text)?;
+    let mut out = Vec::new();
+    let mut run = Vec::new();
+    for line in &lines {
+        if let Line::Operation { .. } = line {
+            run.push(line);
+            continue;
+        }
+        out.extend(format_run(&run));
+        run.clear();
+        out.push(match line {
+            Line::Blank => String::new(),
+            Line::Verbatim(text) | Line::Comment(text) => text.clone(),
+            Line::Label { label, comment } => match comment {
+                Some(comment) => format!("{}: {}", label, comment),
+                None => format!("{}:", label),
+            },
+            Line::Operation { .. } => unreachable!(),
+        });
+    }
+    out.extend(format_run(&run));
+
+    // Comments on their own line are indented like the code they describe
+    let mut indent = "";
+    for (line, text) in lines.iter().zip(out.iter_mut()).rev() {
+        match line {
+            Line::Label { .. } | Line::Verbatim(_) => indent = "",
+            Line::Operation { .. } => indent = INDENT,
+            Line::Comment(_) => text.insert_str(0, indent),
+            _ => (),
+        }
+    }
+
+    while out.last().map(String::as_str) == Some("") {
+        out.pop();
+    }
+    let mut formatted = out.join("\n");
+    formatted.push('\n');
+    Ok(formatted)
+}
+
+fn parse(text: &str) -> Result<Vec<Line>, Error> {
+    let mut lines = Vec::new();
+    let mut in_macro = false;
+    for (line_number, text) in text.lines().enumerate() {
+        let error = |message: String| Error::Parse {
+            line: line_number + 1,
+            message,
+        };
+        let trimmed = text.trim();
+        let directive = trimmed.to_ascii_lowercase();
+        if directive.starts_with("%macro") {
+            in_macro = true;
+        } else if directive.starts_with("%endmacro") {
+            in_macro = false;
+        }
+        if in_macro || trimmed.starts_with('%') {
+            lines.push(Line::Verbatim(text.trim_end().to_string()));
+            continue;
+        }
+        if trimmed.is_empty() {
+            lines.push(Line::Blank);
+            continue;
+        }
+        if trimmed.starts_with("//") {
+            lines.push(Line::Comment(trimmed.to_string()));
+            continue;
+        }
+        if parse_conditional(text, SYNTAX).is_some() || parse_repeat(text, SYNTAX).is_some() {
+            lines.push(Line::Verbatim(trimmed.to_string()));
+            continue;
+        }
+
+        let (statement, rest) = parse_line(text, SYNTAX).map_err(error)?;
+        let rest = rest.trim();
+        let comment = if rest.is_empty() {
+            None
+        } else if rest.starts_with("//") {
+            Some(rest.to_string())
+        } else {
+            return Err(error(format!("Unexpected \"{}\"", rest)));
+        };
+        lines.push(match statement {
+            Some(Statement::Label(label)) => Line::Label { label, comment },
+            Some(Statement::Operation(op)) => {
+                operation_line(&op, source_text(&op.src, trimmed), comment)
+            }
+            Some(Statement::Computed(op, _)) => {
+                // Keep the expression as it was written
+                let (_, rest) =
+                    parse_bracketed(trimmed).ok_or_else(|| error(explain_error(text, SYNTAX)))?;
+                operation_line(
+                    &op,
+                    trimmed[..trimmed.len() - rest.len()].to_string(),
+                    comment,
+                )
+            }
+            Some(Statement::Section(name)) => directive_line("section", &name, comment),
+            Some(Statement::Global(label)) => directive_line("global", &label, comment),
+            Some(Statement::Extern(label)) => directive_line("extern", &label, comment),
+            Some(Statement::Origin(address)) => {
+                directive_line("org", &format!("{:04X}", address), comment)
+            }
+            Some(Statement::Text(_)) => {
+                // Keep the string as it was written, escapes and all
+                let code = trimmed[..trimmed.len() - rest.len()].trim_end();
+                let start = code.find(char::is_whitespace).unwrap_or(code.len());
+                directive_line("string", code[start..].trim_start(), comment)
+            }
+            _ => return Err(error("Unexpected statement".to_string())),
+        });
+    }
+    Ok(lines)
+}
+
+fn operation_line(op: &Operation, src: String, comment: Option<String>) -> Line {
+    Line::Operation {
+        src,
+        dest: op.dest.to_string(),
+        conditions: match (op.cond_carry, op.cond_1) {
+            (true, true) => "if_carry | if_1",
+            (true, false) => "if_carry",
+            (false, true) => "if_1",
+            (false, false) => "",
+        }
+        .to_string(),
+        comment,
+    }
+}
+
+/// Directives go at the start of the line, like labels
+fn directive_line(directive: &str, name: &str, comment: Option<String>) -> Line {
+    Line::Verbatim(match comment {
+        Some(comment) => format!(".{} {} {}", directive, name, comment),
+        None => format!(".{} {}", directive, name),
+    })
+}
+
+/// Character literals are kept as they were written, everything else in its canonical spelling
+fn source_text(src: &Source, text: &str) -> String {
+    if let Source::Operand(_) = src {
+        if let Ok((_, rest)) = parse_char_literal(text, SYNTAX.latin1) {
+            return text[..text.len() - rest.len()].to_string();
+        }
+    }
+    src.to_string()
+}
+
+/// Lay out a run of consecutive instructions in columns
+fn format_run(run: &[&Line]) -> Vec<String> {
+    let columns = |column: fn(&Line) -> usize| run.iter().map(|l| column(l)).max().unwrap_or(0);
+    let src_width = columns(|line| match line {
+        Line::Operation { src, .. } => src.chars().count(),
+        _ => 0,
+    });
+    let any_conditions = run
+        .iter()
+        .any(|line| matches!(line, Line::Operation { conditions, .. } if !conditions.is_empty()));
+    let dest_width = columns(|line| match line {
+        Line::Operation { dest, .. } => dest.len(),
+        _ => 0,
+    });
+
+    let code = run
+        .iter()
+        .map(|line| match line {
+            Line::Operation {
+                src,
+                dest,
+                conditions,
+                ..
+            } => {
+                let mut code = format!("{}{:<w$} -> ", INDENT, src, w = src_width);
+                if any_conditions {
+                    code.push_str(&format!("{:<w$}", dest, w = dest_width));
+                    if !conditions.is_empty() {
+                        code.push_str(" : ");
+                        code.push_str(conditions);
+                    }
+                } else {
+                    code.push_str(dest);
+                }
+                code.trim_end().to_string()
+            }
+            _ => unreachable!(),
+        })
+        .collect::<Vec<_>>();
+    let code_width = code.iter().map(|c| c.chars().count()).max().unwrap_or(0);
+
+    code.into_iter()
+        .zip(run)
+        .map(|(code, line)| match line {
+            Line::Operation {
+                comment: Some(comment),
+                ..
+            } => format!("{:<w$} {}", code, comment, w = code_width),
+            _ => code,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let text = "
+// Count up on the LEDs
+%define STEP 01
+\t00 -> ACC
+main_loop:   // Forever
+ACC -> LED // Show it
+ 01 -> ACC.plus
+\tlo@main_loop -> PC.latch : if_1 | if_carry   // Wrap around
+  // Go back
+    lo@main_loop ->   PC
+%macro out 1
+  %1 -> Serial.out
+%endmacro
+' ' -> Serial.out
+'\\'' -> Serial.out
+
+
+";
+        let expected = "
+// Count up on the LEDs
+%define STEP 01
+    00 -> ACC
+main_loop: // Forever
+    ACC          -> LED                        // Show it
+    01           -> ACC.plus
+    lo@main_loop -> PC.latch : if_carry | if_1 // Wrap around
+    // Go back
+    lo@main_loop -> PC
+%macro out 1
+  %1 -> Serial.out
+%endmacro
+    ' '  -> Serial.out
+    '\\'' -> Serial.out
+";
+        let formatted = format(text).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_columns() {
+        let text = "loop:\nlo@loop -> PC.latch : if_1\nACC -> LED\n5F -> RAM.low : if_carry // x";
+        let formatted = format(text).unwrap();
+        assert_eq!(
+            formatted,
+            "loop:
+    lo@loop -> PC.latch : if_1
+    ACC     -> LED
+    5F      -> RAM.low  : if_carry // x
+"
+        );
+        assert_eq!(
+            assembler::assemble(&formatted).unwrap(),
+            assembler::assemble(text).unwrap()
+        );
+        // Aliases and odd capitalization come out in the canonical spelling
+        assert_eq!(format("mem -> out").unwrap(), "    RAM -> Serial.out\n");
+        assert_eq!(
+            format("  .ifdef SERIAL\nACC -> Serial.out\n  .endif").unwrap(),
+            ".ifdef SERIAL\n    ACC -> Serial.out\n.endif\n"
+        );
+        assert_eq!(
+            format("  .GLOBAL  main // Entry\nmain:").unwrap(),
+            ".global main // Entry\nmain:\n"
+        );
+        assert_eq!(format(".org 100").unwrap(), ".org 0100\n");
+        assert_eq!(
+            format("  .STRING  \"a // b\\n\"  // Banner").unwrap(),
+            ".string \"a // b\\n\" // Banner\n"
+        );
+        assert_eq!(
+            format(".rept 4, i\n(i * 2) -> LED\nACC -> ACC\n .endr").unwrap(),
+            ".rept 4, i\n    (i * 2) -> LED\n    ACC     -> ACC\n.endr\n"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            format("ACC -> ACC\nACC -> ACC garbage"),
+            Err(Error::Parse { line: 2, .. })
+        ));
+        assert!(format("loop: ACC -> ACC").is_err());
+        assert!(format("ACC.plus -> LED").is_err());
+    }
+}
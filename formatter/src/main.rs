@@ -0,0 +1,56 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{self, Read, Write};
+
+const USAGE: &str = "Usage: [--check] [<source.s>...]
+Formats the files in place, or stdin to stdout if none are given. With --check, lists the \
+files that aren't formatted and fails instead.";
+
+fn main() -> Result<()> {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if arg.starts_with("--") => bail!(USAGE),
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.is_empty() {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)?;
+        let formatted = formatter::format(&text)?;
+        if check {
+            if formatted != text {
+                bail!("<stdin> is not formatted");
+            }
+        } else {
+            io::stdout().write_all(formatted.as_bytes())?;
+        }
+        return Ok(());
+    }
+
+    let mut unformatted = 0;
+    for path in &paths {
+        let text = fs::read_to_string(path).with_context(|| format!("Reading {}", path))?;
+        let formatted = formatter::format(&text).with_context(|| format!("Formatting {}", path))?;
+        if formatted == text {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            unformatted += 1;
+        } else {
+            fs::write(path, formatted)?;
+        }
+    }
+    if unformatted > 0 {
+        bail!("{} file(s) are not formatted", unformatted);
+    }
+    Ok(())
+}
@@ -0,0 +1,7 @@
+#![no_main]
+use assembler::assemble;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|text: &str| {
+    let _ = assemble(text);
+});
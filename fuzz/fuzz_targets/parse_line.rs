@@ -0,0 +1,12 @@
+#![no_main]
+use assembler::{parse_line, Syntax};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|text: &str| {
+    for &strict in &[false, true] {
+        for &latin1 in &[false, true] {
+            // Explains the error if the line doesn't parse, which mustn't panic either
+            let _ = parse_line(text, Syntax { strict, latin1 });
+        }
+    }
+});
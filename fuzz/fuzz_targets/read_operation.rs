@@ -0,0 +1,11 @@
+#![no_main]
+use emulator::decoder::read_operation;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for program_counter in 0..=data.len() {
+        if let Ok((_, advance)) = read_operation(data, program_counter) {
+            assert!(program_counter + advance <= data.len());
+        }
+    }
+});
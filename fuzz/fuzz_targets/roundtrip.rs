@@ -0,0 +1,25 @@
+#![no_main]
+use assembler::{assemble_for, AssemblerError, Error, Options};
+use common::Reset;
+use emulator::decoder::disassemble;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let text = match disassemble(data) {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    let options = Options {
+        reset: Reset {
+            skip_first_fetch: false,
+            ..Reset::default()
+        },
+        ..Options::default()
+    };
+    match assemble_for(&text, &options) {
+        Ok(assembly) => assert_eq!(assembly.bytes, data, "{}", text),
+        // Operands can't be written to RAM, but the encoding exists
+        Err(Error::Assembler(AssemblerError::ForbiddenInstruction { .. })) => (),
+        Err(e) => panic!("{}\n{}", e, text),
+    }
+});
@@ -0,0 +1,32 @@
+#![no_main]
+use common::Reset;
+use emulator::Emulator;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Start at 0 so that every ROM runs, not just those starting with a NOP
+    let reset = Reset {
+        skip_first_fetch: false,
+        ..Reset::default()
+    };
+    let program = data.to_vec().into_boxed_slice();
+    let mut emulator = match Emulator::with_reset(program.clone(), reset.clone()) {
+        Ok(emulator) => emulator,
+        Err(_) => return,
+    };
+    emulator.enable_history(16);
+    // Stepping with and without the predecoded table should behave the same
+    let mut predecoded = Emulator::with_reset(program, reset).unwrap();
+    predecoded.predecode();
+    for _ in 0..1000 {
+        let result = emulator.step();
+        assert_eq!(result.is_ok(), predecoded.step().is_ok());
+        assert_eq!(emulator.signals(), predecoded.signals());
+        assert_eq!(emulator.cycles(), predecoded.cycles());
+        assert_eq!(emulator.take_serial(), predecoded.take_serial());
+        if result.is_err() {
+            break;
+        }
+    }
+    while emulator.step_back() {}
+});
@@ -0,0 +1,429 @@
+use assembler::object::{Object, RelocationKind};
+use common::*;
+use std::collections::HashMap;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum LinkError {
+    #[error("Linker script error on line {line}; {message}")]
+    Script { line: usize, message: String },
+    #[error("Symbol {symbol} is defined in both {first} and {second}")]
+    DuplicateSymbol {
+        symbol: Label,
+        first: String,
+        second: String,
+    },
+    #[error("Undefined symbol {symbol} in {object}")]
+    UndefinedSymbol { symbol: Label, object: String },
+    #[error("Section {section} of {object} is missing")]
+    MissingSection { section: String, object: String },
+    #[error("Section {section} appears more than once in {object}")]
+    DuplicateSection { section: String, object: String },
+    #[error("Relocation at {offset:04X} is outside section {section} of {object}")]
+    RelocationOutside {
+        section: String,
+        offset: u16,
+        object: String,
+    },
+    #[error("Section {section} overlaps something else at {address:04X}")]
+    Overlap { section: String, address: u16 },
+    #[error("Section {section} runs past the end of the address space")]
+    TooLarge { section: String },
+}
+
+/// Where to put a section; `None` follows on from the section before
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub section: String,
+    pub address: Option<u16>,
+}
+
+/// The order and addresses of sections, one `<section> [<hex address>]` line each. Sections that
+/// aren't mentioned follow the last one that is, in the order the objects first use them.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Script {
+    pub placements: Vec<Placement>,
+}
+
+impl FromStr for Script {
+    type Err = LinkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut placements = Vec::new();
+        for (line_number, line) in s.lines().enumerate() {
+            let error = |message: &str| LinkError::Script {
+                line: line_number + 1,
+                message: message.to_string(),
+            };
+            let code = line.split("//").next().unwrap_or("");
+            let mut words = code.split_whitespace();
+            let section = match words.next() {
+                Some(section) if is_label(section) => section.to_string(),
+                Some(_) => return Err(error("Expected a section name")),
+                None => continue,
+            };
+            let address = match words.next() {
+                Some(address) => Some(
+                    u16::from_str_radix(address, 16)
+                        .map_err(|_| error("Expected a hex address"))?,
+                ),
+                None => None,
+            };
+            if words.next().is_some() {
+                return Err(error("Unexpected text after the address"));
+            }
+            placements.push(Placement { section, address });
+        }
+        Ok(Script { placements })
+    }
+}
+
+/// An object file and the name to call it in errors
+#[derive(Debug, Clone, PartialEq)]
+pub struct Input {
+    pub name: String,
+    pub object: Object,
+}
+
+/// How to link a program
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub script: Script,
+    /// Sections start at the reset vector, after the dummy NOP if the board needs one
+    pub reset: Reset,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Linked {
+    pub bytes: Vec<u8>,
+    /// The address of every global symbol
+    pub symbols: HashMap<Label, u16>,
+}
+
+/// Combine `objects` into a ROM image. Libraries are only linked if they define a symbol that's
+/// used but not defined by the objects, or by other libraries that are linked.
+pub fn link(
+    objects: &[Input],
+    libraries: &[Input],
+    options: &Options,
+) -> Result<Linked, LinkError> {
+    let inputs = select(objects, libraries);
+    for input in &inputs {
+        check(input)?;
+    }
+
+    let mut globals = HashMap::new();
+    for (index, input) in inputs.iter().enumerate() {
+        for symbol in input.object.symbols.iter().filter(|symbol| symbol.global) {
+            if let Some(first) = globals.insert(symbol.name.as_str(), (index, symbol)) {
+                return Err(LinkError::DuplicateSymbol {
+                    symbol: symbol.name.clone(),
+                    first: inputs[first.0].name.clone(),
+                    second: input.name.clone(),
+                });
+            }
+        }
+    }
+
+    // Lay the sections out in the order the script gives, then the order they're first used
+    let mut names = Vec::new();
+    let placed = options.script.placements.iter().map(|p| p.section.as_str());
+    let used = inputs
+        .iter()
+        .flat_map(|input| input.object.sections.iter().map(|s| s.name.as_str()));
+    for name in placed.chain(used) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    let mut image = Vec::new();
+    let reset = &options.reset;
+    if reset.skip_first_fetch {
+        place(&mut image, reset.vector, &[NOP_BYTE], "")?;
+    }
+    let mut bases = HashMap::new();
+    let mut cursor = reset.entry_point() as usize;
+    for name in names {
+        let placement = options.script.placements.iter().find(|p| p.section == name);
+        if let Some(address) = placement.and_then(|p| p.address) {
+            cursor = address as usize;
+        }
+        for (index, input) in inputs.iter().enumerate() {
+            if let Some(section) = input.object.sections.iter().find(|s| s.name == name) {
+                if cursor + section.bytes.len() > 0x10000 {
+                    return Err(LinkError::TooLarge {
+                        section: name.to_string(),
+                    });
+                }
+                place(&mut image, cursor as u16, &section.bytes, name)?;
+                bases.insert((index, name), cursor as u16);
+                cursor += section.bytes.len();
+            }
+        }
+    }
+
+    let address = |index: usize, symbol: &Label| {
+        let input: &Input = inputs[index];
+        let (index, symbol) = match input.object.symbol(symbol) {
+            Some(local) => (index, local),
+            None => *globals
+                .get(symbol.as_str())
+                .ok_or_else(|| LinkError::UndefinedSymbol {
+                    symbol: symbol.clone(),
+                    object: input.name.clone(),
+                })?,
+        };
+        match bases.get(&(index, symbol.section.as_str())) {
+            Some(base) => Ok(base.wrapping_add(symbol.offset)),
+            None => Err(LinkError::MissingSection {
+                section: symbol.section.clone(),
+                object: inputs[index].name.clone(),
+            }),
+        }
+    };
+    for (index, input) in inputs.iter().enumerate() {
+        for section in &input.object.sections {
+            let base = bases[&(index, section.name.as_str())];
+            for relocation in &section.relocations {
+                let target = address(index, &relocation.symbol)?;
+                let byte = match relocation.kind {
+                    RelocationKind::Lo => (target & 0x00FF) as u8,
+                    RelocationKind::Hi => (target >> 8) as u8,
+                };
+                image[base.wrapping_add(relocation.offset) as usize] = Some(byte);
+            }
+        }
+    }
+
+    let mut symbols = HashMap::new();
+    for (name, (index, _)) in &globals {
+        symbols.insert(name.to_string(), address(*index, &name.to_string())?);
+    }
+    while let Some(None) = image.last() {
+        image.pop();
+    }
+    Ok(Linked {
+        bytes: image.into_iter().map(|b| b.unwrap_or(NOP_BYTE)).collect(),
+        symbols,
+    })
+}
+
+/// Make sure an object, which may have been written by hand, has each section once and only
+/// relocates bytes inside its sections
+fn check(input: &Input) -> Result<(), LinkError> {
+    let sections = &input.object.sections;
+    for (index, section) in sections.iter().enumerate() {
+        if sections[..index].iter().any(|s| s.name == section.name) {
+            return Err(LinkError::DuplicateSection {
+                section: section.name.clone(),
+                object: input.name.clone(),
+            });
+        }
+        let outside = section
+            .relocations
+            .iter()
+            .find(|r| r.offset as usize >= section.bytes.len());
+        if let Some(relocation) = outside {
+            return Err(LinkError::RelocationOutside {
+                section: section.name.clone(),
+                offset: relocation.offset,
+                object: input.name.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The objects, followed by whichever libraries they need
+fn select<'a>(objects: &'a [Input], libraries: &'a [Input]) -> Vec<&'a Input> {
+    let mut inputs = objects.iter().collect::<Vec<_>>();
+    let mut linked = vec![false; libraries.len()];
+    loop {
+        let defined = |symbol: &str| {
+            inputs
+                .iter()
+                .any(|input| matches!(input.object.symbol(symbol), Some(s) if s.global))
+        };
+        let undefined = inputs
+            .iter()
+            .flat_map(|input| {
+                let relocations = input.object.sections.iter().flat_map(|s| &s.relocations);
+                relocations
+                    .filter(move |r| input.object.symbol(&r.symbol).is_none())
+                    .map(|r| r.symbol.as_str())
+            })
+            .filter(|symbol| !defined(symbol))
+            .collect::<Vec<_>>();
+        let next = libraries.iter().enumerate().find(|(index, library)| {
+            !linked[*index]
+                && undefined
+                    .iter()
+                    .any(|symbol| matches!(library.object.symbol(symbol), Some(s) if s.global))
+        });
+        match next {
+            Some((index, library)) => {
+                linked[index] = true;
+                inputs.push(library);
+            }
+            None => return inputs,
+        }
+    }
+}
+
+/// Copy `bytes` into the image at `address`, making sure nothing is there already
+fn place(
+    image: &mut Vec<Option<u8>>,
+    address: u16,
+    bytes: &[u8],
+    section: &str,
+) -> Result<(), LinkError> {
+    let start = address as usize;
+    if image.len() < start + bytes.len() {
+        image.resize(start + bytes.len(), None);
+    }
+    for (offset, byte) in bytes.iter().enumerate() {
+        let slot = &mut image[start + offset];
+        if slot.is_some() {
+            return Err(LinkError::Overlap {
+                section: section.to_string(),
+                address: (start + offset) as u16,
+            });
+        }
+        *slot = Some(*byte);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::{assemble, assemble_object};
+
+    fn input(name: &str, text: &str) -> Input {
+        Input {
+            name: name.to_string(),
+            object: assemble_object(text, &Default::default()).unwrap(),
+        }
+    }
+
+    const MAIN: &str = "
+.extern print
+.global main
+main:
+    'a' -> ACC
+    lo@print -> PC.latch
+    hi@print -> PC";
+
+    const PRINT: &str = "
+.global print
+print:
+    ACC -> Serial.out
+    lo@main -> PC
+.extern main";
+
+    #[test]
+    fn test_link() {
+        let main = input("main.o", MAIN);
+        let print = input("print.o", PRINT);
+        let unused = input("unused.o", ".global unused\nunused:\nACC -> LED");
+        let linked = link(&[main], &[unused, print], &Default::default()).unwrap();
+
+        // Linking gives the same program as assembling everything together
+        let together = format!("{}\n{}", MAIN, PRINT);
+        assert_eq!(linked.bytes, assemble(&together).unwrap());
+        assert_eq!(linked.symbols["main"], 0x0001);
+        assert_eq!(linked.symbols["print"], 0x0007);
+        assert_eq!(linked.symbols.len(), 2);
+    }
+
+    #[test]
+    fn test_script() {
+        let script = "
+text 0001 // Right after the dummy NOP
+lib 0010"
+            .parse::<Script>()
+            .unwrap();
+        let options = Options {
+            script,
+            ..Default::default()
+        };
+        let main = input("main.o", MAIN);
+        let print = input("print.o", &format!(".section lib\n{}", PRINT));
+        let data = input("data.o", ".section data\n.global table\ntable:\n5F -> ACC");
+        let linked = link(&[main, print, data], &[], &options).unwrap();
+        assert_eq!(linked.symbols["print"], 0x0010);
+        assert_eq!(linked.symbols["table"], 0x0013);
+        assert_eq!(&linked.bytes[0x0003..0x0007], &[0xD0, 0x10, 0xD4, 0x00]);
+
+        assert!("text 10000".parse::<Script>().is_err());
+        assert!("text 0000 0001".parse::<Script>().is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let main = || input("main.o", MAIN);
+        let print = || input("print.o", PRINT);
+        assert_eq!(
+            link(&[main()], &[], &Default::default()),
+            Err(LinkError::UndefinedSymbol {
+                symbol: "print".into(),
+                object: "main.o".into()
+            })
+        );
+        assert_eq!(
+            link(&[main(), print(), print()], &[], &Default::default()),
+            Err(LinkError::DuplicateSymbol {
+                symbol: "print".into(),
+                first: "print.o".into(),
+                second: "print.o".into()
+            })
+        );
+        let options = Options {
+            script: "text 0000".parse().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(
+            link(&[main(), print()], &[], &options),
+            Err(LinkError::Overlap {
+                section: "text".into(),
+                address: 0x0000
+            })
+        );
+        let options = Options {
+            script: "text FFFE".parse().unwrap(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            link(&[main(), print()], &[], &options),
+            Err(LinkError::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_malformed_objects() {
+        let mut main = input("main.o", MAIN);
+        let mut text = main.object.sections[0].clone();
+        text.bytes.truncate(2);
+        main.object.sections.push(text);
+        assert_eq!(
+            link(&[main, input("print.o", PRINT)], &[], &Default::default()),
+            Err(LinkError::DuplicateSection {
+                section: "text".into(),
+                object: "main.o".into()
+            })
+        );
+
+        let mut main = input("main.o", MAIN);
+        main.object.sections[0].relocations[1].offset = 0x0100;
+        assert_eq!(
+            link(&[main, input("print.o", PRINT)], &[], &Default::default()),
+            Err(LinkError::RelocationOutside {
+                section: "text".into(),
+                offset: 0x0100,
+                object: "main.o".into()
+            })
+        );
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::{bail, Context, Result};
+use assembler::format_symbols;
+use assembler::object::Object;
+use common::args::Args;
+use linker::{link, Input, Options};
+use std::fs;
+
+const USAGE: &str = "Usage: <object.o>... -o <output_path> [--library <object.o>]... \
+[--script <script>] [--symbols <symbols_path>] [--reset-vector <hex>] [--no-dummy-nop]
+Libraries are only linked if the program uses one of their symbols.";
+
+fn read(path: &str) -> Result<Input> {
+    let text = fs::read_to_string(path).with_context(|| format!("Reading {}", path))?;
+    let object = text
+        .parse::<Object>()
+        .with_context(|| format!("Reading {}", path))?;
+    Ok(Input {
+        name: path.to_string(),
+        object,
+    })
+}
+
+fn main() -> Result<()> {
+    let mut args = Args::from_env();
+    let mut output_path = None;
+    let mut symbols_path = None;
+    let mut options = Options::default();
+    let mut objects = Vec::new();
+    let mut libraries = Vec::new();
+    while let Some(arg) = args.next() {
+        if args.reset(&arg, &mut options.reset)? {
+            continue;
+        }
+        match arg.as_str() {
+            "-o" => output_path = Some(args.value(&arg)?),
+            "--library" => libraries.push(read(&args.value(&arg)?)?),
+            "--script" => {
+                let path = args.value(&arg)?;
+                options.script = fs::read_to_string(&path)?.parse()?;
+            }
+            "--symbols" => symbols_path = Some(args.value(&arg)?),
+            _ if arg.starts_with('-') => bail!(USAGE),
+            _ => objects.push(read(&arg)?),
+        }
+    }
+    let output_path = match output_path {
+        Some(path) if !objects.is_empty() => path,
+        _ => bail!(USAGE),
+    };
+
+    let linked = link(&objects, &libraries, &options)?;
+    fs::write(output_path, &linked.bytes)?;
+    if let Some(path) = symbols_path {
+        fs::write(path, format_symbols(&linked.symbols))?;
+    }
+    Ok(())
+}
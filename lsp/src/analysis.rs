@@ -0,0 +1,510 @@
+use assembler::conditional::{Conditions, Defines};
+use assembler::{
+    assemble_statements, object, parse, parse_conditional, parse_line, parse_repeat, prologue,
+    Assembly, Error, IntoInstruction, Options, Repeat, Statement, Syntax, ADDRESS_SPACE,
+};
+use common::*;
+use lsp_types::*;
+
+const SOURCES: [Source; 3] = [Source::Expansion, Source::Accumulator, Source::Memory];
+
+const DESTINATIONS: [Destination; 16] = [
+    Destination::Memory,
+    Destination::AccumulatorPlus,
+    Destination::AccumulatorNand,
+    Destination::Accumulator,
+    Destination::ProgramCounterLatch,
+    Destination::ProgramCounter,
+    Destination::MemAddressLo,
+    Destination::MemAddressHi,
+    Destination::Serial,
+    Destination::Led,
+    Destination::CarrySet,
+    Destination::CarryReset,
+    Destination::ExpansionSelect,
+    Destination::Spare(SpareDestination::D),
+    Destination::Spare(SpareDestination::E),
+    Destination::Spare(SpareDestination::F),
+];
+
+const CONDITIONS: [&str; 2] = ["if_carry", "if_1"];
+
+/// Everything the server knows about one document, rebuilt whenever it changes
+#[derive(Debug, Default)]
+pub struct Analysis {
+    lines: Vec<String>,
+    /// The operation on each line, if any
+    operations: Vec<Option<Operation>>,
+    pub definitions: Vec<(Label, Range)>,
+    pub references: Vec<(Label, Range)>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// `None` if the document doesn't assemble
+    assembly: Option<Assembly>,
+}
+
+impl Analysis {
+    pub fn new(text: &str) -> Self {
+        let mut analysis = Analysis {
+            lines: text.lines().map(str::to_string).collect(),
+            ..Default::default()
+        };
+        let mut statements = prologue(&Reset::default());
+        // Symbols come from the command line, so check the build where none are defined
+        let mut conditions = Conditions::default();
+        // The lines open `.rept` blocks start on. Each outermost block is expanded by the
+        // assembler once it's closed, so that its labels are renamed the same way.
+        let mut repeats = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            if let Some(conditional) = parse_conditional(line, Syntax::default()) {
+                if let Err(message) = conditions.update(&conditional, &Defines::new(), index + 1) {
+                    analysis.error(index, message);
+                }
+                analysis.operations.push(None);
+                continue;
+            }
+            if !conditions.active() {
+                analysis.operations.push(None);
+                continue;
+            }
+            if let Some(repeat) = parse_repeat(line, Syntax::default()) {
+                match repeat {
+                    Repeat::Start { .. } => repeats.push(index),
+                    Repeat::End => match repeats.pop() {
+                        None => analysis.error(index, "\".endr\" without \".rept\"".to_string()),
+                        Some(start) if repeats.is_empty() => {
+                            let block = &analysis.lines[start..=index];
+                            match parse(&block.join("\n"), &Options::default()) {
+                                Ok(expanded) => statements.extend(
+                                    expanded
+                                        .into_iter()
+                                        .map(|(statement, line)| (statement, start + line)),
+                                ),
+                                Err(Error::Parse { line, message }) => {
+                                    analysis.error_once(start + line - 1, message)
+                                }
+                                Err(e) => analysis.error(start, e.to_string()),
+                            }
+                        }
+                        Some(_) => (),
+                    },
+                }
+                analysis.operations.push(None);
+                continue;
+            }
+            let statement = match parse_line(line, Syntax::default()) {
+                Ok((statement, _)) => statement,
+                Err(message) => {
+                    analysis.error(index, message);
+                    None
+                }
+            };
+            match &statement {
+                Some(Statement::Label(label)) => {
+                    let start = line.find(label.as_str()).unwrap_or(0);
+                    let range = span(line, index, start, start + label.len());
+                    analysis.definitions.push((label.clone(), range));
+                }
+                Some(Statement::Global(label)) => {
+                    let directive = line.find('.').unwrap_or(0);
+                    let start = line[directive..]
+                        .find(char::is_whitespace)
+                        .map_or(0, |i| directive + i);
+                    let start = start + line[start..].find(label.as_str()).unwrap_or(0);
+                    let range = span(line, index, start, start + label.len());
+                    analysis.references.push((label.clone(), range));
+                }
+                Some(Statement::Operation(op)) => {
+                    if let Source::LabelLo(label) | Source::LabelHi(label) = &op.src {
+                        let start = line.find('@').map_or(0, |i| i + 1);
+                        let range = span(line, index, start, start + label.len());
+                        analysis.references.push((label.clone(), range));
+                    }
+                }
+                _ => (),
+            }
+            analysis.operations.push(match &statement {
+                Some(Statement::Operation(op)) => Some(op.clone()),
+                _ => None,
+            });
+            match statement {
+                Some(statement) if repeats.is_empty() => statements.push((statement, index + 1)),
+                _ => (),
+            }
+        }
+
+        if let Some(line) = conditions.unclosed() {
+            analysis.error(line - 1, "Missing \".endif\"".to_string());
+        }
+        if let Some(&line) = repeats.last() {
+            analysis.error(line, "Missing \".endr\"".to_string());
+        }
+
+        // Labels from other objects can't be resolved here, so only check what the linker would
+        let object = statements
+            .iter()
+            .any(|(statement, _)| matches!(statement, Statement::Extern(_)));
+        if object {
+            if let Err(e) = object::assemble(&statements) {
+                analysis.error(e.line() - 1, e.to_string());
+            }
+            return analysis;
+        }
+        match assemble_statements(&statements, ADDRESS_SPACE) {
+            Ok(assembly) => {
+                for warning in &assembly.warnings {
+                    analysis.diagnostics.push(Diagnostic {
+                        range: analysis.line_range(warning.line() - 1),
+                        severity: Some(DiagnosticSeverity::WARNING),
+                        message: warning.to_string(),
+                        ..Default::default()
+                    });
+                }
+                analysis.assembly = Some(assembly);
+            }
+            Err(Error::Assembler(e)) => analysis.error(e.line() - 1, e.to_string()),
+            Err(e) => analysis.error(0, e.to_string()),
+        }
+        analysis
+    }
+
+    fn error(&mut self, line: usize, message: String) {
+        self.diagnostics.push(Diagnostic {
+            range: self.line_range(line),
+            severity: Some(DiagnosticSeverity::ERROR),
+            message,
+            ..Default::default()
+        });
+    }
+
+    /// Report an error unless the line already has one, as when a line in a `.rept` block fails
+    /// to parse both on its own and when the block is expanded
+    fn error_once(&mut self, line: usize, message: String) {
+        if !self
+            .diagnostics
+            .iter()
+            .any(|d| d.range.start.line as usize == line)
+        {
+            self.error(line, message);
+        }
+    }
+
+    /// The whole of a line, without leading and trailing whitespace
+    fn line_range(&self, line: usize) -> Range {
+        let text = self.lines.get(line).map_or("", String::as_str);
+        let start = text.len() - text.trim_start().len();
+        span(text, line, start, text.trim_end().len().max(start))
+    }
+
+    /// The label defined or referenced at `position`
+    pub fn label_at(&self, position: Position) -> Option<&Label> {
+        self.definitions
+            .iter()
+            .chain(self.references.iter())
+            .find(|(_, range)| range.start <= position && position <= range.end)
+            .map(|(label, _)| label)
+    }
+
+    pub fn definition(&self, label: &str) -> Option<Range> {
+        self.definitions
+            .iter()
+            .find(|(l, _)| l == label)
+            .map(|(_, range)| *range)
+    }
+
+    pub fn references_to(&self, label: &str, include_definition: bool) -> Vec<Range> {
+        let definitions = self.definitions.iter().filter(|_| include_definition);
+        definitions
+            .chain(self.references.iter())
+            .filter(|(l, _)| l == label)
+            .map(|(_, range)| *range)
+            .collect()
+    }
+
+    /// A label's address, or the encoding of the instruction on the line
+    pub fn hover(&self, position: Position) -> Option<String> {
+        if let Some(label) = self.label_at(position) {
+            let address = self.assembly.as_ref()?.symbols.get(label)?;
+            return Some(format!("`{}` at `{:04X}`", label, address));
+        }
+        let line = position.line as usize;
+        let op = self.operations.get(line)?.as_ref()?;
+        let located = self.assembly.as_ref().and_then(|assembly| {
+            let map = &assembly.source_map.bytes;
+            let address = map
+                .iter()
+                .position(|b| matches!(b, Some(b) if b.location.line == line + 1))?;
+            let length = if let Source::Expansion | Source::Accumulator | Source::Memory = op.src {
+                1
+            } else {
+                2
+            };
+            Some((address, &assembly.bytes[address..address + length]))
+        });
+        let mut text = format!("`{}`\n\n", op);
+        match located {
+            Some((address, bytes)) => {
+                text.push_str(&format!("`{:04X}`:", address));
+                for byte in bytes {
+                    text.push_str(&format!(" `{:02X}`", byte));
+                }
+            }
+            None => text.push_str(&format!("`{:02X}`", op.instruction_bits())),
+        }
+        text.push_str(&format!(" (`{:08b}`)", op.instruction_bits()));
+        Some(text)
+    }
+
+    /// Keywords that may come next at `position`: destinations after `->`, conditions after
+    /// `:`, otherwise sources
+    pub fn completions(&self, position: Position) -> Vec<CompletionItem> {
+        let line = self
+            .lines
+            .get(position.line as usize)
+            .map_or("", String::as_str);
+        let before = &line[..byte_index(line, position.character)];
+        let keyword = |label: String, detail: &str| CompletionItem {
+            label,
+            kind: Some(CompletionItemKind::KEYWORD),
+            detail: Some(detail.to_string()),
+            ..Default::default()
+        };
+        let arrow = before.find("->");
+        if before.contains("//") {
+            Vec::new()
+        } else if arrow
+            .filter(|arrow| before[*arrow..].contains(':'))
+            .is_some()
+        {
+            CONDITIONS
+                .iter()
+                .map(|c| keyword(c.to_string(), "condition"))
+                .collect()
+        } else if arrow.is_some() {
+            DESTINATIONS
+                .iter()
+                .map(|d| keyword(d.to_string(), "destination"))
+                .collect()
+        } else {
+            let labels = self.definitions.iter().flat_map(|(label, _)| {
+                let reference = |prefix: &str| CompletionItem {
+                    label: format!("{}@{}", prefix, label),
+                    kind: Some(CompletionItemKind::REFERENCE),
+                    detail: Some("label".to_string()),
+                    ..Default::default()
+                };
+                vec![reference("lo"), reference("hi")]
+            });
+            SOURCES
+                .iter()
+                .map(|s| keyword(s.to_string(), "source"))
+                .chain(labels)
+                .collect()
+        }
+    }
+
+    /// Every label, spanning the lines up to the next one
+    #[allow(deprecated)]
+    pub fn symbols(&self) -> Vec<DocumentSymbol> {
+        let last = Position::new(self.lines.len() as u32, 0);
+        self.definitions
+            .iter()
+            .enumerate()
+            .map(|(i, (label, selection))| {
+                let end = self
+                    .definitions
+                    .get(i + 1)
+                    .map_or(last, |(_, next)| Position::new(next.start.line, 0));
+                DocumentSymbol {
+                    name: label.clone(),
+                    detail: None,
+                    kind: SymbolKind::FUNCTION,
+                    tags: None,
+                    deprecated: None,
+                    range: Range::new(Position::new(selection.start.line, 0), end),
+                    selection_range: *selection,
+                    children: None,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Range covering bytes `start..end` of `line`, which is line `index` of the document
+fn span(line: &str, index: usize, start: usize, end: usize) -> Range {
+    let column = |byte: usize| line[..byte].encode_utf16().count() as u32;
+    Range::new(
+        Position::new(index as u32, column(start)),
+        Position::new(index as u32, column(end)),
+    )
+}
+
+/// Byte index of a UTF-16 column, which is how LSP counts characters
+fn byte_index(line: &str, character: u32) -> usize {
+    let mut column = 0;
+    for (i, c) in line.char_indices() {
+        if column >= character as usize {
+            return i;
+        }
+        column += c.len_utf16();
+    }
+    line.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "start:
+5F -> LED // Light up
+lo@start -> PC.latch
+hi@start -> PC : if_1
+unused:";
+
+    #[test]
+    fn test_labels() {
+        let analysis = Analysis::new(TEXT);
+        let start = analysis.definition("start").unwrap();
+        assert_eq!(start, Range::new(Position::new(0, 0), Position::new(0, 5)));
+        assert_eq!(
+            analysis.label_at(Position::new(2, 5)).map(String::as_str),
+            Some("start")
+        );
+        assert_eq!(analysis.references_to("start", false).len(), 2);
+        assert_eq!(analysis.references_to("start", true)[0], start);
+        assert_eq!(analysis.label_at(Position::new(1, 0)), None);
+
+        let symbols = analysis.symbols();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[0].range.end, Position::new(4, 0));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let analysis = Analysis::new(TEXT);
+        assert_eq!(analysis.diagnostics.len(), 1);
+        assert_eq!(
+            analysis.diagnostics[0].severity,
+            Some(DiagnosticSeverity::WARNING)
+        );
+        assert_eq!(analysis.diagnostics[0].range.start.line, 4);
+
+        let analysis = Analysis::new("ACC -> LED\n  ACC -> nowhere\nlo@missing -> PC");
+        let errors = analysis
+            .diagnostics
+            .iter()
+            .map(|d| (d.range, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    Range::new(Position::new(1, 2), Position::new(1, 16)),
+                    "Unrecognized destination \"nowhere\""
+                ),
+                (
+                    Range::new(Position::new(2, 0), Position::new(2, 16)),
+                    "Unrecognized label \"missing\", line: 3"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_object() {
+        let analysis = Analysis::new(".extern print\n.global main\nmain:\nlo@print -> PC");
+        assert!(analysis.diagnostics.is_empty());
+        assert_eq!(
+            analysis.references_to("main", false)[0].start,
+            Position::new(1, 8)
+        );
+
+        let analysis = Analysis::new(".extern print\nlo@prnt -> PC");
+        assert_eq!(analysis.diagnostics[0].range.start.line, 1);
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let text = ".ifdef SERIAL\nout:\nnot even code\n.else\nout:\n.endif\nlo@out -> PC";
+        let analysis = Analysis::new(text);
+        assert!(analysis.diagnostics.is_empty());
+        assert_eq!(analysis.definition("out").unwrap().start.line, 4);
+
+        let analysis = Analysis::new(".if 1\n.else\n.else");
+        let errors = analysis
+            .diagnostics
+            .iter()
+            .map(|d| (d.range.start.line, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (2, "Second \".else\" for the \".if\" on line 1"),
+                (0, "Missing \".endif\"")
+            ]
+        );
+
+        // Labels in a block are renamed for each iteration, as the assembler does
+        let analysis = Analysis::new(".rept 3, i\nstep:\n(i * 2) -> LED\nlo@step -> PC\n.endr");
+        assert!(analysis.diagnostics.is_empty());
+        assert_eq!(analysis.definition("step").unwrap().start.line, 1);
+        let analysis = Analysis::new(".rept 3, i\nstep:\n(i * 2) -> LED\n.endr\nlo@step -> PC");
+        let errors = analysis
+            .diagnostics
+            .iter()
+            .map(|d| (d.range.start.line, d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(errors, vec![(4, "Unrecognized label \"step\", line: 5")]);
+        assert_eq!(
+            Analysis::new(".rept 2\nACC -> nowhere\n.endr")
+                .diagnostics
+                .len(),
+            1
+        );
+        assert_eq!(
+            Analysis::new(".rept 2").diagnostics[0].message,
+            "Missing \".endr\""
+        );
+    }
+
+    #[test]
+    fn test_hover() {
+        let analysis = Analysis::new(TEXT);
+        assert_eq!(
+            analysis.hover(Position::new(3, 4)).unwrap(),
+            "`start` at `0001`"
+        );
+        assert_eq!(
+            analysis.hover(Position::new(1, 1)).unwrap(),
+            "`5F -> LED`\n\n`0001`: `E4` `5F` (`11100100`)"
+        );
+        assert_eq!(analysis.hover(Position::new(0, 6)), None);
+    }
+
+    #[test]
+    fn test_completions() {
+        let analysis = Analysis::new(TEXT);
+        let labels = |position| {
+            analysis
+                .completions(position)
+                .into_iter()
+                .map(|c| c.label)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            labels(Position::new(1, 0)),
+            vec![
+                "EXP",
+                "ACC",
+                "RAM",
+                "lo@start",
+                "hi@start",
+                "lo@unused",
+                "hi@unused"
+            ]
+        );
+        assert!(labels(Position::new(1, 6)).contains(&"Serial.out".to_string()));
+        assert!(labels(Position::new(1, 6)).contains(&"spare.F".to_string()));
+        assert_eq!(labels(Position::new(3, 17)), vec!["if_carry", "if_1"]);
+        assert!(labels(Position::new(1, 21)).is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::Result;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as NotificationTrait, PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, References,
+    Request as RequestTrait,
+};
+use lsp_types::*;
+use std::collections::HashMap;
+
+mod analysis;
+use analysis::Analysis;
+
+fn main() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    run(&connection)?;
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![">".into(), ":".into(), "|".into()]),
+            ..Default::default()
+        }),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    }
+}
+
+/// Serve requests until the client shuts the server down
+fn run(connection: &Connection) -> Result<()> {
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+    let mut documents = HashMap::new();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                let response = respond(&documents, request);
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                if let Some(uri) = update(&mut documents, notification)? {
+                    let diagnostics = documents
+                        .get(&uri)
+                        .map_or_else(Vec::new, |a: &Analysis| a.diagnostics.clone());
+                    let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+                    connection
+                        .sender
+                        .send(Message::Notification(Notification::new(
+                            PublishDiagnostics::METHOD.to_string(),
+                            params,
+                        )))?;
+                }
+            }
+            Message::Response(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Keep track of open documents, returning the one that changed
+fn update(
+    documents: &mut HashMap<Url, Analysis>,
+    notification: Notification,
+) -> Result<Option<Url>> {
+    Ok(match notification.method.as_str() {
+        DidOpenTextDocument::METHOD => {
+            let params: DidOpenTextDocumentParams = serde_json::from_value(notification.params)?;
+            let document = params.text_document;
+            documents.insert(document.uri.clone(), Analysis::new(&document.text));
+            Some(document.uri)
+        }
+        DidChangeTextDocument::METHOD => {
+            let params: DidChangeTextDocumentParams = serde_json::from_value(notification.params)?;
+            let uri = params.text_document.uri;
+            // With full sync the last change holds the whole document
+            if let Some(change) = params.content_changes.last() {
+                documents.insert(uri.clone(), Analysis::new(&change.text));
+            }
+            Some(uri)
+        }
+        DidCloseTextDocument::METHOD => {
+            let params: DidCloseTextDocumentParams = serde_json::from_value(notification.params)?;
+            documents.remove(&params.text_document.uri);
+            Some(params.text_document.uri)
+        }
+        _ => None,
+    })
+}
+
+fn respond(documents: &HashMap<Url, Analysis>, request: Request) -> Response {
+    let id = request.id.clone();
+    match handle(documents, request) {
+        Ok(result) => Response::new_ok(id, result),
+        Err(e) => Response::new_err(id, ErrorCode::InvalidParams as i32, e.to_string()),
+    }
+}
+
+fn handle(documents: &HashMap<Url, Analysis>, request: Request) -> Result<serde_json::Value> {
+    let document = |uri: &Url| documents.get(uri);
+    let value = match request.method.as_str() {
+        GotoDefinition::METHOD => {
+            let params: GotoDefinitionParams = serde_json::from_value(request.params)?;
+            let position = params.text_document_position_params.position;
+            let uri = params.text_document_position_params.text_document.uri;
+            let range = document(&uri).and_then(|analysis| {
+                let label = analysis.label_at(position)?;
+                analysis.definition(label)
+            });
+            let response =
+                range.map(|range| GotoDefinitionResponse::from(Location::new(uri, range)));
+            serde_json::to_value(response)?
+        }
+        References::METHOD => {
+            let params: ReferenceParams = serde_json::from_value(request.params)?;
+            let position = params.text_document_position.position;
+            let uri = params.text_document_position.text_document.uri;
+            let include_declaration = params.context.include_declaration;
+            let locations = document(&uri).and_then(|analysis| {
+                let label = analysis.label_at(position)?;
+                let ranges = analysis.references_to(label, include_declaration);
+                Some(
+                    ranges
+                        .into_iter()
+                        .map(|range| Location::new(uri.clone(), range))
+                        .collect::<Vec<_>>(),
+                )
+            });
+            serde_json::to_value(locations)?
+        }
+        HoverRequest::METHOD => {
+            let params: HoverParams = serde_json::from_value(request.params)?;
+            let position = params.text_document_position_params;
+            let hover = document(&position.text_document.uri)
+                .and_then(|analysis| analysis.hover(position.position))
+                .map(|value| Hover {
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value,
+                    }),
+                    range: None,
+                });
+            serde_json::to_value(hover)?
+        }
+        Completion::METHOD => {
+            let params: CompletionParams = serde_json::from_value(request.params)?;
+            let position = params.text_document_position;
+            let items = document(&position.text_document.uri)
+                .map(|analysis| analysis.completions(position.position));
+            serde_json::to_value(items.map(CompletionResponse::Array))?
+        }
+        DocumentSymbolRequest::METHOD => {
+            let params: DocumentSymbolParams = serde_json::from_value(request.params)?;
+            let symbols = document(&params.text_document.uri)
+                .map(|analysis| DocumentSymbolResponse::Nested(analysis.symbols()));
+            serde_json::to_value(symbols)?
+        }
+        method => anyhow::bail!("Unsupported request {}", method),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::thread;
+
+    /// Drive the server through an in-memory connection, the same way a client would over stdio
+    #[test]
+    fn test_session() {
+        let (server, client) = Connection::memory();
+        let server = thread::spawn(move || run(&server).unwrap());
+        let uri = "file:///test.s";
+        let request = |id: i32, method: &str, params: serde_json::Value| {
+            client
+                .sender
+                .send(Message::Request(Request::new(
+                    id.into(),
+                    method.into(),
+                    params,
+                )))
+                .unwrap();
+            loop {
+                match client.receiver.recv().unwrap() {
+                    Message::Response(response) => return response.result.unwrap(),
+                    _ => continue,
+                }
+            }
+        };
+        let notify = |method: &str, params: serde_json::Value| {
+            client
+                .sender
+                .send(Message::Notification(Notification::new(
+                    method.into(),
+                    params,
+                )))
+                .unwrap();
+        };
+
+        let result = request(1, "initialize", json!({ "capabilities": {} }));
+        assert_eq!(result["capabilities"]["hoverProvider"], true);
+        notify("initialized", json!({}));
+
+        let text = "loop:\n01 -> ACC.plus\nlo@loop -> PC\nACC -> nowhere";
+        notify(
+            DidOpenTextDocument::METHOD,
+            json!({ "textDocument": { "uri": uri, "languageId": "8ttacc", "version": 1, "text": text } }),
+        );
+        let diagnostics = loop {
+            match client.receiver.recv().unwrap() {
+                Message::Notification(n) if n.method == PublishDiagnostics::METHOD => {
+                    break n.params
+                }
+                _ => continue,
+            }
+        };
+        assert_eq!(diagnostics["diagnostics"][0]["range"]["start"]["line"], 3);
+
+        let position =
+            json!({ "textDocument": { "uri": uri }, "position": { "line": 2, "character": 4 } });
+        let result = request(2, GotoDefinition::METHOD, position.clone());
+        assert_eq!(result["range"]["start"]["line"], 0);
+        let result = request(3, HoverRequest::METHOD, position);
+        assert_eq!(result["contents"]["value"], "`loop` at `0001`");
+
+        request(4, "shutdown", json!(null));
+        notify("exit", json!(null));
+        server.join().unwrap();
+    }
+}
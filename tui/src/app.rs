@@ -0,0 +1,273 @@
+use crossterm::event::KeyCode;
+use emulator::profile::{instruction_addresses, Symbols};
+use emulator::Emulator;
+use std::num::NonZeroU64;
+use std::time::{Duration, Instant};
+
+/// Clock speeds to choose from while running, in Hz
+const SPEEDS: [u64; 8] = [1, 10, 100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+fn speed_hz(speed: usize) -> NonZeroU64 {
+    NonZeroU64::new(SPEEDS[speed]).unwrap_or(NonZeroU64::MIN)
+}
+
+/// How long to spend running instructions before drawing again
+const FRAME: Duration = Duration::from_millis(30);
+/// How much of the serial output to keep, which is more than fits on screen
+const SERIAL_LIMIT: usize = 0x1000;
+
+/// Bytes in a row of the RAM view
+pub const RAM_ROW: usize = 16;
+
+/// One line of the disassembly pane
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub labels: Vec<String>,
+    /// `None` if the bytes don't decode
+    pub text: Option<String>,
+    pub current: bool,
+    /// Whether the condition passes, for the current instruction if it has one
+    pub taken: Option<bool>,
+}
+
+pub struct App {
+    pub emulator: Emulator,
+    pub symbols: Symbols,
+    pub running: bool,
+    /// Index into `SPEEDS`
+    speed: usize,
+    /// When running started, and the cycle count then
+    started: (Instant, u64),
+    /// The last `SERIAL_LIMIT` bytes the program has written to `Serial.out`
+    pub serial: Vec<u8>,
+    /// Why the emulator last stopped, if it was an error
+    pub error: Option<String>,
+    pub quit: bool,
+    /// Instruction addresses when the ROM is read linearly from the start
+    addresses: Vec<usize>,
+}
+
+impl App {
+    pub fn new(mut emulator: Emulator, symbols: Symbols) -> Self {
+        emulator.predecode();
+        let speed = SPEEDS
+            .iter()
+            .position(|hz| *hz >= emulator.timing.clock_hz.get())
+            .unwrap_or(SPEEDS.len() - 1);
+        emulator.timing.clock_hz = speed_hz(speed);
+        Self {
+            addresses: instruction_addresses(&emulator.program),
+            started: (Instant::now(), emulator.cycles()),
+            emulator,
+            symbols,
+            running: false,
+            speed,
+            serial: Vec::new(),
+            error: None,
+            quit: false,
+        }
+    }
+
+    pub fn clock_hz(&self) -> u64 {
+        SPEEDS[self.speed]
+    }
+
+    pub fn handle_key(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Char(' ') => {
+                self.running = !self.running;
+                self.restart_clock();
+            }
+            KeyCode::Char('s') | KeyCode::Right if !self.running => self.step(),
+            KeyCode::Char('+') | KeyCode::Char('=') => self.set_speed(self.speed + 1),
+            KeyCode::Char('-') => self.set_speed(self.speed.saturating_sub(1)),
+            KeyCode::Char('r') => {
+                self.running = false;
+                self.error = None;
+                self.serial.clear();
+                if let Err(e) = self.emulator.reset() {
+                    self.error = Some(e.to_string());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn set_speed(&mut self, speed: usize) {
+        self.speed = speed.min(SPEEDS.len() - 1);
+        self.emulator.timing.clock_hz = speed_hz(self.speed);
+        self.restart_clock();
+    }
+
+    fn restart_clock(&mut self) {
+        self.started = (Instant::now(), self.emulator.cycles());
+    }
+
+    pub fn step(&mut self) {
+        if let Err(e) = self.emulator.step() {
+            self.error = Some(e.to_string());
+            self.running = false;
+        }
+        self.serial.extend(self.emulator.take_serial());
+        let excess = self.serial.len().saturating_sub(SERIAL_LIMIT);
+        self.serial.drain(..excess);
+    }
+
+    /// Run as many instructions as the clock speed allows since running started, giving up after
+    /// a frame so the screen stays responsive
+    pub fn tick(&mut self) {
+        let frame = Instant::now();
+        while self.running && frame.elapsed() < FRAME {
+            let (start, start_cycles) = self.started;
+            let elapsed = start.elapsed().as_nanos() * self.clock_hz() as u128 / 1_000_000_000;
+            if self.emulator.cycles() >= start_cycles + elapsed as u64 {
+                break;
+            }
+            self.step();
+        }
+    }
+
+    /// `rows` instructions with the one at the program counter roughly in the middle
+    pub fn disassembly(&self, rows: usize) -> Vec<Instruction> {
+        let pc = self.emulator.pc.get() as usize;
+        // Jumping into the middle of an instruction puts the decoding out of step with reading
+        // from the start, so decode from the program counter instead
+        let mut addresses = match self.addresses.binary_search(&pc) {
+            Ok(index) => self.addresses[index.saturating_sub(rows / 2)..].to_vec(),
+            Err(_) => vec![pc],
+        };
+        addresses.truncate(rows);
+        let mut address = *addresses.last().unwrap_or(&pc);
+        while addresses.len() < rows && address < self.emulator.program.len() {
+            address += self.length(address);
+            if address < self.emulator.program.len() && !addresses.contains(&address) {
+                addresses.push(address);
+            } else {
+                break;
+            }
+        }
+
+        addresses
+            .into_iter()
+            .map(|address| {
+                let op = self
+                    .emulator
+                    .predecoded
+                    .as_ref()
+                    .and_then(|p| p.get(address as u16));
+                let end = (address + self.length(address)).min(self.emulator.program.len());
+                let current = address == pc;
+                Instruction {
+                    address: address as u16,
+                    bytes: self.emulator.program[address..end].to_vec(),
+                    labels: self
+                        .symbols
+                        .iter()
+                        .filter(|(a, _)| *a as usize == address)
+                        .map(|(_, label)| label.clone())
+                        .collect(),
+                    text: op.map(|op| op.operation().to_string()),
+                    current,
+                    taken: op
+                        .filter(|op| current && (op.cond_1 || op.cond_carry))
+                        .map(|op| self.emulator.condition_passes(op.cond_1, op.cond_carry)),
+                }
+            })
+            .collect()
+    }
+
+    fn length(&self, address: usize) -> usize {
+        let op = self
+            .emulator
+            .predecoded
+            .as_ref()
+            .and_then(|p| p.get(address as u16));
+        op.map_or(1, |op| op.length as usize)
+    }
+
+    /// `rows` rows of RAM around the address in the latches, with the address of each
+    pub fn ram(&self, rows: usize) -> Vec<(u16, [u8; RAM_ROW])> {
+        let rows = rows.min(0x10000 / RAM_ROW);
+        let row = self.emulator.mem.address() as usize / RAM_ROW;
+        let last = 0x10000 / RAM_ROW - rows;
+        let first = row.saturating_sub(rows / 2).min(last);
+        (first..first + rows)
+            .map(|row| {
+                let address = (row * RAM_ROW) as u16;
+                let mut bytes = [0; RAM_ROW];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.emulator.mem.peek(address + i as u16);
+                }
+                (address, bytes)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(text: &str) -> App {
+        let program = assembler::assemble(text).unwrap();
+        let emulator = Emulator::from_program(program.into_boxed_slice()).unwrap();
+        App::new(emulator, vec![(0x0001, "start".to_string())])
+    }
+
+    #[test]
+    fn test_disassembly() {
+        let mut app = app("start:\n5F -> LED\nACC -> ACC\nlo@start -> PC : if_carry");
+        // Past the dummy NOP
+        app.step();
+        let lines = app.disassembly(3);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].text.as_deref(), Some("ACC -> ACC"));
+        assert!(lines[1].current);
+        assert_eq!(lines[1].labels, vec!["start".to_string()]);
+        assert_eq!(lines[1].bytes, vec![0b1110_0100, 0x5F]);
+
+        app.step();
+        app.step();
+        let lines = app.disassembly(3);
+        let current = lines.iter().find(|line| line.current).unwrap();
+        assert_eq!(current.address, 0x0004);
+        assert_eq!(current.taken, Some(false));
+        assert_eq!(lines[0].address, 0x0003);
+
+        // A jump into the middle of an instruction is decoded from there
+        app.emulator.pc.set(0x0002);
+        let text = app.disassembly(1)[0].text.clone().unwrap();
+        assert!(text.starts_with("ACC -> RAM.high"));
+    }
+
+    #[test]
+    fn test_controls() {
+        let mut app = app("00 -> RAM.low\n01 -> RAM.high\n'a' -> Serial.out\nACC -> RAM");
+        for _ in 0..5 {
+            app.handle_key(KeyCode::Char('s'));
+        }
+        assert_eq!(app.serial, b"a");
+        assert_eq!(app.emulator.mem.address(), 0x0100);
+        let ram = app.ram(4);
+        assert_eq!(ram[2].0, 0x0100);
+
+        let speed = app.clock_hz();
+        app.handle_key(KeyCode::Char('+'));
+        assert_eq!(app.clock_hz(), speed * 10);
+        app.handle_key(KeyCode::Char('r'));
+        assert_eq!(app.emulator.pc.get(), 0x0000);
+        assert!(app.serial.is_empty());
+
+        app.serial = vec![b'x'; SERIAL_LIMIT];
+        for _ in 0..5 {
+            app.handle_key(KeyCode::Char('s'));
+        }
+        assert_eq!(app.serial.len(), SERIAL_LIMIT);
+        assert_eq!(app.serial.last(), Some(&b'a'));
+        app.handle_key(KeyCode::Char('q'));
+        assert!(app.quit);
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::{bail, Result};
+use common::args::Args;
+use common::Reset;
+use crossterm::event::{self, Event, KeyEventKind};
+use emulator::expansion::SpareDestinations;
+use emulator::profile::parse_symbols;
+use emulator::Emulator;
+use std::fs;
+use std::num::NonZeroU64;
+use std::time::Duration;
+
+mod app;
+mod ui;
+use app::App;
+
+const USAGE: &str = "Usage: <file_name.bin> [--symbols <file.sym>] [--clock <hz>] \
+    [--reset-vector <hex>] [--no-dummy-nop] [--spare-destinations <error|nop>]";
+
+fn main() -> Result<()> {
+    let mut args = Args::from_env();
+    let mut path = None;
+    let mut symbols_path = None;
+    let mut clock_hz = None;
+    let mut reset = Reset::default();
+    let mut spare_destinations = SpareDestinations::default();
+    while let Some(arg) = args.next() {
+        if args.reset(&arg, &mut reset)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--symbols" => symbols_path = Some(args.value(&arg)?),
+            "--clock" => clock_hz = Some(args.parse::<NonZeroU64>(&arg)?),
+            "--spare-destinations" => spare_destinations = args.parse(&arg)?,
+            _ if path.is_none() => path = Some(arg),
+            _ => bail!(USAGE),
+        }
+    }
+    if spare_destinations == SpareDestinations::Expansion {
+        bail!("The TUI has no expansion bus to hand spare destinations to");
+    }
+    let path = match path {
+        Some(v) => v,
+        None => bail!(USAGE),
+    };
+    let symbols = match symbols_path {
+        Some(path) => parse_symbols(&fs::read_to_string(path)?)?,
+        None => Vec::new(),
+    };
+
+    let program = fs::read(path)?;
+    let mut emulator = Emulator::with_reset(program.into_boxed_slice(), reset)?;
+    emulator.spare_destinations = spare_destinations;
+    if let Some(hz) = clock_hz {
+        emulator.timing.clock_hz = hz;
+    }
+    let mut app = App::new(emulator, symbols);
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &mut app);
+    ratatui::restore();
+    result
+}
+
+fn run(terminal: &mut ratatui::DefaultTerminal, app: &mut App) -> Result<()> {
+    while !app.quit {
+        terminal.draw(|frame| ui::draw(frame, app))?;
+        // Don't wait for keys while running, just check for them between frames
+        let timeout = if app.running {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(100)
+        };
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    app.handle_key(key.code);
+                }
+            }
+        }
+        app.tick();
+    }
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use crate::app::{App, RAM_ROW};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use ratatui::Frame;
+
+const KEYS: &str = " space run/pause  s step  +/- speed  r reset  q quit ";
+
+pub fn draw(frame: &mut Frame, app: &App) {
+    let [main, status] =
+        Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+    let [left, right] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(main);
+    let [registers, leds, ram] = Layout::vertical([
+        Constraint::Length(7),
+        Constraint::Length(3),
+        Constraint::Min(0),
+    ])
+    .areas(right);
+    let [code, serial] =
+        Layout::vertical([Constraint::Percentage(65), Constraint::Percentage(35)]).areas(left);
+
+    draw_disassembly(frame, app, code);
+    draw_serial(frame, app, serial);
+    draw_registers(frame, app, registers);
+    draw_leds(frame, app, leds);
+    draw_ram(frame, app, ram);
+    draw_status(frame, app, status);
+}
+
+/// The height inside a bordered block
+fn inner_height(area: Rect) -> usize {
+    area.height.saturating_sub(2) as usize
+}
+
+fn draw_disassembly(frame: &mut Frame, app: &App, area: Rect) {
+    let lines = app
+        .disassembly(inner_height(area))
+        .into_iter()
+        .map(|instruction| {
+            let bytes = instruction
+                .bytes
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let mut text = format!(
+                "{} {:04X}  {:<5}  ",
+                if instruction.current { ">" } else { " " },
+                instruction.address,
+                bytes
+            );
+            for label in &instruction.labels {
+                text.push_str(label);
+                text.push_str(": ");
+            }
+            text.push_str(instruction.text.as_deref().unwrap_or("??"));
+            let mut spans = vec![Span::raw(text)];
+            match instruction.taken {
+                Some(true) => spans.push(Span::styled("  taken", Style::new().fg(Color::Green))),
+                Some(false) => spans.push(Span::styled("  skipped", Style::new().fg(Color::Red))),
+                None => (),
+            }
+            let line = Line::from(spans);
+            if instruction.current {
+                line.style(Style::new().add_modifier(Modifier::REVERSED))
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Disassembly ")),
+        area,
+    );
+}
+
+fn draw_registers(frame: &mut Frame, app: &App, area: Rect) {
+    let emu = &app.emulator;
+    let flag = |set| if set { "1" } else { "0" };
+    let lines = vec![
+        Line::from(format!(
+            "PC   {:04X}    PC.latch  {:02X}",
+            emu.pc.get(),
+            emu.pc.latch
+        )),
+        Line::from(format!(
+            "ACC  {:02X}      {:08b}",
+            emu.acc.get(),
+            emu.acc.get()
+        )),
+        Line::from(format!(
+            "RAM  {:04X}    RAM.low {:02X}  RAM.high {:02X}",
+            emu.mem.address(),
+            emu.mem.low_latch,
+            emu.mem.hi_latch
+        )),
+        Line::from(format!(
+            "Flags  1: {}  carry: {}    EXP.sel {:02X}",
+            flag(emu.flag_1),
+            flag(emu.flag_carry),
+            emu.expansion_select
+        )),
+        Line::from(format!("Cycles {}", emu.cycles())),
+    ];
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Registers ")),
+        area,
+    );
+}
+
+fn draw_leds(frame: &mut Frame, app: &App, area: Rect) {
+    let value = app.emulator.led.get();
+    let spans = (0..8)
+        .rev()
+        .map(|bit| {
+            let style = if value & (1 << bit) != 0 {
+                Style::new().fg(Color::Red)
+            } else {
+                Style::new().fg(Color::DarkGray)
+            };
+            Span::styled(" ● ", style)
+        })
+        .collect::<Vec<_>>();
+    frame.render_widget(
+        Paragraph::new(Line::from(spans)).block(Block::bordered().title(" LEDs ")),
+        area,
+    );
+}
+
+fn draw_ram(frame: &mut Frame, app: &App, area: Rect) {
+    let latched = app.emulator.mem.address();
+    let lines = app
+        .ram(inner_height(area))
+        .into_iter()
+        .map(|(address, bytes)| {
+            let mut spans = vec![Span::raw(format!("{:04X} ", address))];
+            for (i, byte) in bytes.iter().enumerate() {
+                let style = if address + i as u16 == latched {
+                    Style::new().add_modifier(Modifier::REVERSED)
+                } else {
+                    Style::new()
+                };
+                spans.push(Span::raw(" "));
+                spans.push(Span::styled(format!("{:02X}", byte), style));
+            }
+            Line::from(spans)
+        })
+        .collect::<Vec<_>>();
+    let title = format!(" RAM ({} bytes per row) ", RAM_ROW);
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(title)),
+        area,
+    );
+}
+
+fn draw_serial(frame: &mut Frame, app: &App, area: Rect) {
+    let text = String::from_utf8_lossy(&app.serial);
+    // Keep the most recent output in view
+    let lines = text.lines().count() + text.ends_with('\n') as usize;
+    let scroll = lines.saturating_sub(inner_height(area)) as u16;
+    frame.render_widget(
+        Paragraph::new(text.to_string())
+            .wrap(Wrap { trim: false })
+            .scroll((scroll, 0))
+            .block(Block::bordered().title(" Serial ")),
+        area,
+    );
+}
+
+fn draw_status(frame: &mut Frame, app: &App, area: Rect) {
+    let state = match (&app.error, app.running) {
+        (Some(error), _) => {
+            Span::styled(format!(" Stopped: {} ", error), Style::new().fg(Color::Red))
+        }
+        (None, true) => Span::styled(" Running ", Style::new().fg(Color::Green)),
+        (None, false) => Span::raw(" Paused "),
+    };
+    let line = Line::from(vec![
+        state,
+        Span::raw(format!(" {} Hz ", app.clock_hz())),
+        Span::styled(KEYS, Style::new().fg(Color::DarkGray)),
+    ]);
+    frame.render_widget(Paragraph::new(line), area);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::Emulator;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    #[test]
+    fn test_draw() {
+        let program = assembler::assemble("5F -> LED\n'h' -> Serial.out").unwrap();
+        let emulator = Emulator::from_program(program.into_boxed_slice()).unwrap();
+        let mut app = App::new(emulator, Vec::new());
+        app.step();
+        app.step();
+        let mut terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+        terminal.draw(|frame| draw(frame, &app)).unwrap();
+        let screen = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect::<String>();
+        assert!(screen.contains("> 0003  E0 68  68 -> Serial.out"));
+        assert!(screen.contains("5F -> LED"));
+        assert!(screen.contains("ACC  00"));
+        assert!(screen.contains(" Paused "));
+    }
+}